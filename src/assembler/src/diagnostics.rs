@@ -0,0 +1,403 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::Range;
+
+use color_eyre::eyre::Report;
+use pest::error::{ErrorVariant, InputLocation};
+use pest::iterators::Pair;
+use pest::RuleType;
+
+use crate::parser::{parse_with, ParseOptions};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+	Error,
+	Warning,
+}
+
+
+/// A problem with a program, pointing at the part of the source the user wrote that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub message: String,
+	/// The byte range in the source the problem is in. Problems inside macro expansions point at the macro call, and
+	/// problems in included files point at the include directive, so the range is always in the source that was assembled.
+	pub span: Range<usize>,
+	/// Extra information about the problem, EG where something it refers to was defined.
+	pub notes: Vec<String>,
+	/// A suggestion for how to fix the problem.
+	pub help: Option<String>,
+}
+
+
+/// A line and column in a source file, both starting at 1. Columns are counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineColumn {
+	pub line: usize,
+	pub column: usize,
+}
+
+
+impl LineColumn {
+	/// Finds the line and column of the byte `offset` in `source`. Offsets past the end are at the end, and offsets inside
+	/// a character are at the start of the character.
+	pub fn of(source: &str, offset: usize) -> Self {
+		let mut offset = offset.min(source.len());
+		while !source.is_char_boundary(offset) {
+			offset -= 1;
+		}
+		let before = &source[..offset];
+		let line_start = before.rfind('\n').map_or(0, |v| v + 1);
+		Self {
+			line: before.matches('\n').count() + 1,
+			column: before[line_start..].chars().count() + 1,
+		}
+	}
+}
+
+
+impl Diagnostic {
+	pub fn start(&self, source: &str) -> LineColumn {
+		LineColumn::of(source, self.span.start)
+	}
+	pub fn end(&self, source: &str) -> LineColumn {
+		LineColumn::of(source, self.span.end)
+	}
+
+	/// The message followed by the notes and the help, one per line, for editors that only show a message.
+	pub fn full_message(&self) -> String {
+		let mut message = self.message.clone();
+		for note in &self.notes {
+			message += &format!("\nnote: {note}");
+		}
+		if let Some(help) = &self.help {
+			message += &format!("\nhelp: {help}");
+		}
+		message
+	}
+
+	/// Writes the diagnostic like rustc does, with the location, the line it is on with the problem underlined, and the notes and help after it.
+	///
+	/// A span that does not fit in `source` is cut off at its end, since failing would hide the problem being reported.
+	pub fn render(&self, source: &str, file_name: &str) -> String {
+		let start = self.start(source);
+		let line_number = start.line.to_string();
+		let gutter = " ".repeat(line_number.len());
+		let line = source.lines().nth(start.line - 1).unwrap_or("");
+		let line = line.strip_suffix('\r').unwrap_or(line);
+		// Keep tabs in front of the underline, so it lines up with the line above it.
+		let padding: String = line.chars().take(start.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+		let end = self.end(source);
+		let end_column = if end.line == start.line { end.column } else { line.chars().count() + 1 };
+		let underline = "^".repeat(end_column.saturating_sub(start.column).max(1));
+		let severity = match self.severity {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+		};
+
+		let mut rendered = format!("{severity}: {}\n", self.message);
+		rendered += &format!("{gutter}--> {file_name}:{}:{}\n", start.line, start.column);
+		rendered += &format!("{gutter} |\n");
+		rendered += &format!("{line_number} | {line}\n");
+		rendered += &format!("{gutter} | {padding}{underline}\n");
+		for note in &self.notes {
+			rendered += &format!("{gutter} = note: {note}\n");
+		}
+		if let Some(help) = &self.help {
+			rendered += &format!("{gutter} = help: {help}\n");
+		}
+		rendered
+	}
+}
+
+
+/// Assembles `source` and returns every problem with it. The program assembles if none of them are errors.
+pub fn diagnose(source: &str) -> Vec<Diagnostic> {
+	diagnose_with(source, &ParseOptions::default())
+}
+
+
+/// Like [`diagnose`], for programs that are in a file and can include other files.
+pub fn diagnose_with(source: &str, options: &ParseOptions) -> Vec<Diagnostic> {
+	match parse_with(source, options) {
+		Ok(parsed) => parsed.warnings,
+		Err(e) => to_diagnostics(&e),
+	}
+}
+
+
+/// Turns an error from the assembler into a diagnostic. Errors that do not know where they happened point at the start of the source.
+///
+/// Only the first problem is returned when the error holds several, see [`to_diagnostics`] for all of them.
+pub fn to_diagnostic(error: &Report) -> Diagnostic {
+	to_diagnostics(error).remove(0)
+}
+
+
+/// Turns an error from the assembler into a diagnostic for every problem in it.
+pub fn to_diagnostics(error: &Report) -> Vec<Diagnostic> {
+	if let Some(list) = error.downcast_ref::<ErrorList>() {
+		return list.errors.iter().map(SpannedError::to_diagnostic).collect();
+	}
+	match error.downcast_ref::<SpannedError>() {
+		Some(e) => vec![e.to_diagnostic()],
+		None => vec![Diagnostic {
+			severity: Severity::Error,
+			message: error.to_string(),
+			span: 0..0,
+			notes: Vec::new(),
+			help: None,
+		}],
+	}
+}
+
+
+/// Writes every problem in an error from the assembler like rustc does. Returns None if the error does not know where in the source it happened.
+pub fn render_error(error: &Report, source: &str, file_name: &str) -> Option<String> {
+	if error.downcast_ref::<ErrorList>().is_none() && error.downcast_ref::<SpannedError>().is_none() {
+		return None;
+	}
+	let diagnostics = to_diagnostics(error);
+	Some(diagnostics.iter().map(|d| d.render(source, file_name)).collect::<Vec<_>>().join("\n"))
+}
+
+
+/// An error that knows which part of the source caused it.
+#[derive(Debug)]
+pub(crate) struct SpannedError {
+	pub(crate) message: String,
+	pub(crate) span: Range<usize>,
+	/// How the error is printed, if that is different from the message. EG errors from pest include a snippet of the source.
+	pub(crate) rendered: Option<String>,
+	pub(crate) notes: Vec<String>,
+	pub(crate) help: Option<String>,
+}
+
+
+/// Several errors found in one go, in the order they appear in the source.
+#[derive(Debug)]
+pub(crate) struct ErrorList {
+	pub(crate) errors: Vec<SpannedError>,
+}
+
+
+impl SpannedError {
+	pub(crate) fn new(message: String, span: Range<usize>) -> Self {
+		Self {
+			message,
+			span,
+			rendered: None,
+			notes: Vec::new(),
+			help: None,
+		}
+	}
+
+	pub(crate) fn with_note(mut self, note: String) -> Self {
+		self.notes.push(note);
+		self
+	}
+
+	pub(crate) fn with_help(mut self, help: String) -> Self {
+		self.help = Some(help);
+		self
+	}
+
+	fn to_diagnostic(&self) -> Diagnostic {
+		Diagnostic {
+			severity: Severity::Error,
+			message: self.message.clone(),
+			span: self.span.clone(),
+			notes: self.notes.clone(),
+			help: self.help.clone(),
+		}
+	}
+
+	pub(crate) fn to_warning(&self) -> Diagnostic {
+		Diagnostic {
+			severity: Severity::Warning,
+			..self.to_diagnostic()
+		}
+	}
+
+	pub(crate) fn from_pest<R: RuleType>(error: pest::error::Error<R>) -> Report {
+		let span = match error.location {
+			InputLocation::Pos(v) => v..v,
+			InputLocation::Span((start, end)) => start..end,
+		};
+		let message = match &error.variant {
+			ErrorVariant::ParsingError { positives, negatives } => {
+				let mut parts = Vec::new();
+				if !positives.is_empty() {
+					parts.push(format!("expected {}", list_rules(positives)));
+				}
+				if !negatives.is_empty() {
+					parts.push(format!("unexpected {}", list_rules(negatives)));
+				}
+				if parts.is_empty() {
+					"unknown parsing error".to_string()
+				} else {
+					parts.join(", ")
+				}
+			}
+			ErrorVariant::CustomError { message } => message.clone(),
+		};
+		Report::new(Self {
+			rendered: Some(error.to_string()),
+			..Self::new(message, span)
+		})
+	}
+}
+
+
+impl ErrorList {
+	/// Turns the errors into one error. A single error is reported on its own.
+	pub(crate) fn report(mut errors: Vec<SpannedError>) -> Report {
+		errors.sort_by_key(|e| e.span.start);
+		if errors.len() == 1 {
+			Report::new(errors.remove(0))
+		} else {
+			Report::new(Self { errors })
+		}
+	}
+
+	/// Turns problems that were all found in one go into one error that is always a list, even if it holds one problem. The
+	/// assembler reports these as they are, instead of blanking out the line of each one and assembling again.
+	pub(crate) fn found_together(mut errors: Vec<SpannedError>) -> Report {
+		errors.sort_by_key(|e| e.span.start);
+		Report::new(Self { errors })
+	}
+
+	/// Splits an error into the errors it holds. Errors that do not know where they happened are returned as they are.
+	pub(crate) fn into_errors(error: Report) -> Result<Vec<SpannedError>, Report> {
+		match error.downcast::<Self>() {
+			Ok(list) => Ok(list.errors),
+			Err(e) => e.downcast::<SpannedError>().map(|e| vec![e]),
+		}
+	}
+}
+
+
+impl Display for SpannedError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.write_str(self.rendered.as_ref().unwrap_or(&self.message))
+	}
+}
+
+
+impl Error for SpannedError {}
+
+
+impl Display for ErrorList {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		writeln!(f, "Found {} errors:", self.errors.len())?;
+		for error in &self.errors {
+			writeln!(f, "{error}")?;
+		}
+		Ok(())
+	}
+}
+
+
+impl Error for ErrorList {}
+
+
+/// The span of `pair` without the whitespace around it, which many rules in the grammar include.
+pub(crate) fn trimmed_span<R: RuleType>(pair: &Pair<R>) -> Range<usize> {
+	let text = pair.as_str();
+	let start = pair.as_span().start() + (text.len() - text.trim_start().len());
+	let end = pair.as_span().end() - (text.len() - text.trim_end().len());
+	start..end.max(start)
+}
+
+
+/// Finds the name in `candidates` that is closest to `name`, if it is close enough to be a typo.
+pub(crate) fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+	// Short names are close to too many other names for a suggestion to help.
+	let limit = match name.chars().count() {
+		0..=2 => return None,
+		length => (length / 3).max(1),
+	};
+	candidates
+		.into_iter()
+		.filter(|c| *c != name)
+		.map(|c| (edit_distance(&name.to_lowercase(), &c.to_lowercase()), c))
+		.filter(|(distance, _)| *distance <= limit)
+		.min_by_key(|(distance, _)| *distance)
+		.map(|(_, c)| c)
+}
+
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut previous: Vec<usize> = (0..=b.len()).collect();
+	for (i, a) in a.chars().enumerate() {
+		let mut current = vec![i + 1];
+		for (j, b) in b.iter().enumerate() {
+			let substitution = previous[j] + usize::from(a != *b);
+			current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
+
+
+fn list_rules<R: Debug>(rules: &[R]) -> String {
+	let rules: Vec<String> = rules.iter().map(|r| format!("{r:?}")).collect();
+	match rules.as_slice() {
+		[one] => one.clone(),
+		[rest @ .., last] => format!("{} or {last}", rest.join(", ")),
+		[] => String::new(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+
+	fn diagnostic(span: Range<usize>) -> Diagnostic {
+		Diagnostic {
+			severity: Severity::Error,
+			message: "Something is wrong.".to_string(),
+			span,
+			notes: vec!["A note.".to_string()],
+			help: Some("Some help.".to_string()),
+		}
+	}
+
+
+	#[test]
+	fn renders_like_rustc() {
+		let source = "program:\n\tmov 9 9\n";
+		assert_eq!(
+			diagnostic(10..17).render(source, "main.myvm"),
+			"error: Something is wrong.\n --> main.myvm:2:2\n  |\n2 | \tmov 9 9\n  | \t^^^^^^^\n  = note: A note.\n  = help: Some help.\n"
+		);
+	}
+
+
+	#[test]
+	fn spans_outside_the_source_do_not_panic() {
+		let source = "program:\nmov 9 9\n";
+		let rendered = diagnostic(9..100).render(source, "main.myvm");
+		assert!(rendered.contains("--> main.myvm:2:1\n  |\n2 | mov 9 9\n  | ^^^^^^^\n"), "{rendered}");
+		let rendered = diagnostic(100..200).render(source, "main.myvm");
+		assert!(rendered.starts_with("error: Something is wrong.\n --> main.myvm:3:1\n  |\n3 | \n  | ^\n"), "{rendered}");
+		assert!(diagnostic(Range { start: 5, end: 2 }).render(source, "main.myvm").contains("1 | program:\n  |      ^\n"));
+		assert!(diagnostic(0..10).render("", "empty.myvm").contains("--> empty.myvm:1:1\n"));
+	}
+
+
+	#[test]
+	fn offsets_inside_a_character_are_at_its_start() {
+		let source = "é😀";
+		assert_eq!(LineColumn::of(source, 1), LineColumn { line: 1, column: 1 });
+		assert_eq!(LineColumn::of(source, 3), LineColumn { line: 1, column: 2 });
+		assert_eq!(LineColumn::of(source, 6), LineColumn { line: 1, column: 3 });
+		assert!(diagnostic(1..4).render(source, "main.myvm").contains("1 | é😀\n  | ^\n"));
+	}
+}
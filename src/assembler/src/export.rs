@@ -0,0 +1,180 @@
+use std::fmt::Write;
+
+use bytecode_interpreter::container::Container;
+
+use crate::parser::SuccessfulParse;
+
+
+/// Writes the program as text that can be pasted into the program component in Turing Complete.
+///
+/// Every instruction is written as a decimal number on its own line, and labels are written as comments above the instruction they point to.
+pub fn to_turing_complete(parsed: &SuccessfulParse) -> String {
+	let mut out = String::new();
+	out.push_str("# Assembled by my_vm. Paste this into the program component in Turing Complete.\n");
+	let mut labels = parsed.labels().iter().peekable();
+	for (position, instruction) in parsed.program().iter().enumerate() {
+		while let Some((name, _)) = labels.next_if(|(_, p)| *p as usize == position) {
+			writeln!(out, "# label {name}:").unwrap();
+		}
+		writeln!(out, "{instruction}").unwrap();
+	}
+	// Labels after the last instruction, EG a label marking the end of the program.
+	for (name, _) in labels {
+		writeln!(out, "# label {name}:").unwrap();
+	}
+	out
+}
+
+
+/// Packs the program, its input, labels and source lines into a container that needs `extensions` to run.
+pub fn to_container(parsed: &SuccessfulParse, extensions: u8) -> Container {
+	Container {
+		extensions,
+		code: parsed.program().to_vec(),
+		input: parsed.input().to_vec(),
+		symbols: parsed.labels().to_vec(),
+		source_map: parsed.source_lines().iter().enumerate().map(|(address, line)| (address as u8, *line)).collect(),
+	}
+}
+
+
+/// Writes the program in the Intel HEX format, with 16 bytes per record, that most EEPROM programmers and simulators can load.
+pub fn to_intel_hex(program: &[u8]) -> String {
+	let mut out = String::new();
+	for (i, record) in program.chunks(16).enumerate() {
+		let address = (i * 16) as u16;
+		let mut bytes = vec![record.len() as u8, (address >> 8) as u8, address as u8, 0];
+		bytes.extend_from_slice(record);
+		// The checksum is the two's complement of the sum of every other byte in the record.
+		let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+		bytes.push(checksum);
+		out.push(':');
+		for byte in bytes {
+			write!(out, "{byte:02X}").unwrap();
+		}
+		out.push('\n');
+	}
+	out.push_str(":00000001FF\n");
+	out
+}
+
+
+/// Writes the program as a ROM image for Logisim-evolution, which can be loaded into a ROM component with "Load Image...".
+pub fn to_logisim(program: &[u8]) -> String {
+	let mut out = String::from("v2.0 raw\n");
+	for line in program.chunks(16) {
+		let line: Vec<String> = line.iter().map(|b| format!("{b:x}")).collect();
+		writeln!(out, "{}", line.join(" ")).unwrap();
+	}
+	out
+}
+
+
+/// Writes the program in the hex format of the Digital simulator, which is the Logisim format with one byte per line.
+pub fn to_digital(program: &[u8]) -> String {
+	let mut out = String::from("v2.0 raw\n");
+	for byte in program {
+		writeln!(out, "{byte:x}").unwrap();
+	}
+	out
+}
+
+
+/// Writes the program as a C header that defines it as a byte array called `program`.
+pub fn to_c_array(program: &[u8]) -> String {
+	let mut out = String::new();
+	out.push_str("// Assembled by my_vm.\n#pragma once\n\n");
+	writeln!(out, "#define PROGRAM_LENGTH {}", program.len()).unwrap();
+	out.push_str("static const unsigned char program[PROGRAM_LENGTH] = {\n");
+	write_byte_rows(&mut out, program);
+	out.push_str("};\n");
+	out
+}
+
+
+/// Writes the program as Rust source that defines it as a byte array called `PROGRAM`.
+pub fn to_rust_array(program: &[u8]) -> String {
+	let mut out = String::new();
+	out.push_str("// Assembled by my_vm.\n");
+	writeln!(out, "pub const PROGRAM: [u8; {}] = [", program.len()).unwrap();
+	write_byte_rows(&mut out, program);
+	out.push_str("];\n");
+	out
+}
+
+
+/// Writes the program as two digit hex numbers separated by spaces, 16 bytes per line.
+pub fn to_hex(program: &[u8]) -> String {
+	let mut out = String::new();
+	for line in program.chunks(16) {
+		let line: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+		writeln!(out, "{}", line.join(" ")).unwrap();
+	}
+	out
+}
+
+
+fn write_byte_rows(out: &mut String, program: &[u8]) {
+	for line in program.chunks(12) {
+		let line: Vec<String> = line.iter().map(|b| format!("0x{b:02x},")).collect();
+		writeln!(out, "    {}", line.join(" ")).unwrap();
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+
+	#[test]
+	fn intel_hex_records() {
+		assert_eq!(to_intel_hex(&[1, 2, 3]), ":03000000010203F7\n:00000001FF\n");
+		assert_eq!(to_intel_hex(&[]), ":00000001FF\n");
+	}
+
+
+	#[test]
+	fn intel_hex_checksums() {
+		let program: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(7)).collect();
+		let hex = to_intel_hex(&program);
+		let records: Vec<&str> = hex.lines().collect();
+		assert_eq!(records.len(), 4);
+		for (i, record) in records.iter().enumerate() {
+			let bytes: Vec<u8> = (1..record.len()).step_by(2).map(|j| u8::from_str_radix(&record[j..j + 2], 16).unwrap()).collect();
+			// Every byte of a record, including the checksum, adds up to 0.
+			assert_eq!(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0, "{record}");
+			if i < 3 {
+				assert_eq!(bytes[0] as usize, program[i * 16..].len().min(16));
+				assert_eq!(u16::from_be_bytes([bytes[1], bytes[2]]) as usize, i * 16);
+				assert_eq!(&bytes[4..bytes.len() - 1], &program[i * 16..(i * 16 + 16).min(40)]);
+			}
+		}
+	}
+
+
+	#[test]
+	fn text_images() {
+		let program = [0x00, 0x1f, 0xa0, 0xff];
+		assert_eq!(to_logisim(&program), "v2.0 raw\n0 1f a0 ff\n");
+		assert_eq!(to_digital(&program), "v2.0 raw\n0\n1f\na0\nff\n");
+		assert_eq!(to_hex(&program), "00 1f a0 ff\n");
+		assert!(to_c_array(&program).contains("#define PROGRAM_LENGTH 4\n"));
+		assert!(to_c_array(&program).contains("    0x00, 0x1f, 0xa0, 0xff,\n"));
+		assert!(to_rust_array(&program).contains("pub const PROGRAM: [u8; 4] = [\n    0x00, 0x1f, 0xa0, 0xff,\n];"));
+	}
+
+
+	#[test]
+	fn turing_complete_label_comments() {
+		let parsed = crate::parser::parse("program:\nlabel start:\n5\nlabel loop:\nlabel again:\nmov 0 out\nloop\nj\nlabel end:\n").unwrap();
+		let [five, mov, load, jump] = parsed.program() else { panic!("{:?}", parsed.program()) };
+		// Labels at the same address are written in alphabetical order.
+		let expected = format!(
+			"# Assembled by my_vm. Paste this into the program component in Turing Complete.\n\
+			# label start:\n{five}\n# label again:\n# label loop:\n{mov}\n{load}\n{jump}\n# label end:\n"
+		);
+		assert_eq!(to_turing_complete(&parsed), expected);
+		assert_eq!([*five, *load], [5, 1]);
+	}
+}
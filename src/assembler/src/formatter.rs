@@ -0,0 +1,509 @@
+//! Formats myvm assembly in one canonical style.
+//!
+//! The source is lexed with the grammar and written back one instruction per line. `input:`, `program:`, labels and
+//! macro definitions start at the beginning of the line, everything else is indented by four spaces. Comments are kept
+//! where they were, and single blank lines between instructions are kept too.
+use std::ops::Range;
+
+use color_eyre::Result;
+use pest::iterators::Pair;
+
+use crate::diagnostics::trimmed_span;
+use crate::lexer::{lex, Rule};
+use crate::preprocessor::macro_expander::replace_words;
+use crate::symbols::{char_length, string_length, words};
+
+
+const INDENT: &str = "    ";
+
+
+/// Something that is written on its own line, or lines.
+struct Item {
+	/// Where the item is in the source. Comments are looked for between items.
+	span: Range<usize>,
+	indent: usize,
+	text: String,
+	/// Whether there always is an empty line before the item.
+	blank_before: bool,
+}
+
+
+/// Formats `source`. Fails if the source does not lex.
+pub fn format(source: &str) -> Result<String> {
+	let file = lex(source)?;
+	let mut items = Vec::new();
+	let mut has_inputs = false;
+	for node in file.into_inner() {
+		match node.as_rule() {
+			Rule::inputs => {
+				let span = trimmed_span(&node);
+				if span.is_empty() {
+					continue;
+				}
+				has_inputs = true;
+				items.push(item(span.start..span.start + "input:".len(), 0, "input:".to_string()));
+				for line in node.into_inner().filter(|l| l.as_rule() == Rule::input_line) {
+					let values: Vec<String> = line.clone().into_inner().map(|v| format_byte(&v)).collect();
+					items.push(item(trimmed_span(&line), 0, values.join(", ")));
+				}
+			}
+			Rule::program => {
+				let mut actions = node.into_inner();
+				let start = actions.next().unwrap();
+				items.push(Item {
+					blank_before: has_inputs,
+					..item(keyword_span(&start, "program:"), 0, "program:".to_string())
+				});
+				format_actions(actions, 0, 0, 1, &mut items);
+			}
+			_ => (),
+		}
+	}
+	let newline = if source.contains("\r\n") { "\r\n" } else { "\n" };
+	Ok(write_items(source, &items).replace("\r\n", "\n").replace('\n', newline))
+}
+
+
+fn item(span: Range<usize>, indent: usize, text: String) -> Item {
+	Item { span, indent, text, blank_before: false }
+}
+
+
+/// Adds the items for a list of actions. `offset` is added to every span, for macro bodies that are lexed on their own.
+fn format_actions<'a>(
+	actions: impl Iterator<Item = Pair<'a, Rule>>,
+	offset: usize,
+	label_indent: usize,
+	indent: usize,
+	items: &mut Vec<Item>,
+) {
+	for action in actions.filter(|a| a.as_rule() == Rule::action) {
+		let node = action.into_inner().next().unwrap();
+		let span = trimmed_span(&node);
+		let span = span.start + offset..span.end + offset;
+		match node.as_rule() {
+			Rule::instruction => items.push(item(span, indent, format_instruction(node))),
+			Rule::constant => {
+				let mut inner = node.into_inner();
+				let name = inner.next().unwrap().as_str().trim();
+				let value = inner.next().unwrap();
+				let value = match value.clone().into_inner().next() {
+					Some(v) if v.as_rule() == Rule::literal => v.as_str().trim().to_lowercase(),
+					_ => value.as_str().trim().to_string(),
+				};
+				items.push(item(span, indent, format!("{name} = {value}")));
+			}
+			Rule::macro_call => {
+				let mut inner = node.into_inner();
+				let name = inner.next().unwrap().as_str().trim();
+				let mut arguments: Vec<&str> = inner.next().unwrap().into_inner().map(|a| a.as_str().trim()).collect();
+				// A call without arguments still has one empty argument.
+				if arguments == [""] {
+					arguments.clear();
+				}
+				items.push(item(span, indent, format!("{name}({})", arguments.join(", "))));
+			}
+			Rule::label => {
+				let identifier = node.clone().into_inner().next().unwrap();
+				let name = identifier.as_str().trim();
+				let after_name = identifier.as_span().end() - node.as_span().start();
+				let colon = node.as_span().start() + after_name + node.as_str()[after_name..].find(':').unwrap() + 1;
+				items.push(item(span.start..colon + offset, label_indent, format!("label {name}:")));
+			}
+			Rule::use_label_or_const => {
+				let identifier = node.into_inner().next().unwrap();
+				let span = trimmed_span(&identifier);
+				items.push(item(span.start + offset..span.end + offset, indent, identifier.as_str().trim().to_string()));
+			}
+			Rule::include => {
+				let path = node.into_inner().next().unwrap();
+				let end = path.as_span().end() + offset;
+				items.push(item(span.start..end, indent, format!("include {}", path.as_str())));
+			}
+			Rule::full_macro => format_macro(node, offset, label_indent, items),
+			_ => (),
+		}
+	}
+}
+
+
+fn format_macro(node: Pair<Rule>, offset: usize, indent: usize, items: &mut Vec<Item>) {
+	let mut inner = node.into_inner();
+	let definition = inner.next().unwrap();
+	let span = trimmed_span(&definition);
+	let mut definition_inner = definition.into_inner();
+	let name = definition_inner.next().unwrap().as_str().trim();
+	let parameters: Vec<&str> = definition_inner.next().unwrap().into_inner().map(|p| p.as_str().trim()).collect();
+	let text = format!("macro {name}({}):", parameters.join(", "));
+	items.push(item(span.start + offset..span.end + offset, indent, text));
+
+	let body = inner.next().unwrap();
+	let body_start = body.as_span().start() + offset;
+	// Parameters are not valid identifiers, but turning the % into a _ makes them valid without moving anything.
+	let prefix = "program:\n";
+	let lexable = format!("{prefix}{}", body.as_str().replace('%', "_"));
+	match lex(&lexable) {
+		Ok(file) => {
+			let program = file.into_inner().find(|p| p.as_rule() == Rule::program).unwrap();
+			let mut body_items = Vec::new();
+			format_actions(program.into_inner().skip(1), body_start - prefix.len(), indent + 1, indent + 1, &mut body_items);
+			items.extend(join_parameter_operands(body_items, body.as_str(), body_start));
+		}
+		// Bodies that do not lex on their own, like ones that load a parameter with `ldi`, are formatted line by line.
+		Err(_) => {
+			let text = body
+				.as_str()
+				.lines()
+				.map(str::trim)
+				.filter(|l| !l.is_empty())
+				.map(format_body_line)
+				.collect::<Vec<_>>()
+				.join("\n");
+			let span = trimmed_span(&body);
+			if !text.is_empty() {
+				items.push(item(span.start + offset..span.end + offset, indent + 1, text));
+			}
+		}
+	}
+
+	let end = keyword_span(&inner.next().unwrap(), "end_macro:");
+	items.push(item(end.start + offset..end.end + offset, indent, "end_macro:".to_string()));
+}
+
+
+/// The span of the keyword at the start of `node`. Rules like `program:` end with a lookahead for the end of the line,
+/// so the grammar lets them swallow a comment after the keyword.
+fn keyword_span(node: &Pair<Rule>, keyword: &str) -> Range<usize> {
+	let start = trimmed_span(node).start;
+	start..start + keyword.len()
+}
+
+
+/// Puts the parameters back into the items of a macro body, and joins instructions with the parameters they use as
+/// operands back into one line.
+///
+/// What a parameter stands for is only known once the macro is called, so `mov %from 1` lexes as three items. Splitting
+/// it over three lines would change what the expanded code means, so it is joined and formatted token by token.
+fn join_parameter_operands(items: Vec<Item>, body: &str, body_start: usize) -> Vec<Item> {
+	let original = |item: &Item| &body[item.span.start - body_start..item.span.end - body_start];
+	let same_line = |a: &Item, b: &Item| !body[a.span.end - body_start..b.span.start - body_start].contains('\n');
+	let mut kept: Vec<Item> = Vec::new();
+	for current in items {
+		let joins = match kept.last() {
+			Some(last) if same_line(last, &current) => {
+				let words: Vec<String> = original(last).split_whitespace().map(str::to_lowercase).collect();
+				let operand = original(&current).to_lowercase();
+				match words[0].as_str() {
+					"mov" => words.len() < 3,
+					"halt" | "jez" | "jnz" | "jgz" | "jgez" | "jlz" | "jlez" => {
+						words.len() < 4 && (operand.contains('%') || operand.starts_with("reg") || operand == "unsigned")
+					}
+					_ => false,
+				}
+			}
+			_ => false,
+		};
+		if joins {
+			kept.last_mut().unwrap().span.end = current.span.end;
+		} else {
+			kept.push(current);
+		}
+		let last = kept.last_mut().unwrap();
+		let text = original(last);
+		if joins {
+			last.text = format_parameter_line(text);
+		} else if text.contains('%') {
+			last.text = restore_parameters(&last.text, text);
+		}
+	}
+	kept
+}
+
+
+/// Puts the `%` back in front of the parameters in `formatted`, which was formatted from `original` with the `%` of every
+/// parameter lexed as a `_`.
+fn restore_parameters(formatted: &str, original: &str) -> String {
+	let original_words: Vec<&str> = words(original).into_iter().map(|w| &original[w]).collect();
+	replace_words(formatted, |word| {
+		let name = word.strip_prefix('_')?;
+		let parameter = format!("%{name}");
+		(original_words.contains(&parameter.as_str()) && !original_words.contains(&word)).then_some(parameter)
+	})
+}
+
+
+/// Formats a line of a macro body on its own, or keeps it as it is if it does not lex on its own.
+fn format_body_line(line: &str) -> String {
+	if line.contains('%') {
+		return format_parameter_line(line);
+	}
+	let formatted = format(&format!("program:\n{line}")).ok();
+	match formatted.as_deref().and_then(|f| f.strip_prefix("program:\n")).map(str::trim) {
+		Some(formatted) if !formatted.is_empty() && !formatted.contains('\n') => formatted.to_string(),
+		_ => line.to_string(),
+	}
+}
+
+
+/// Formats a line of a macro body that only lexes once the parameters in it are filled in, token by token like
+/// `format_instruction` would. Parameters, literals and names keep their case, and a comment after the code is kept.
+fn format_parameter_line(line: &str) -> String {
+	let (tokens, comment) = parameter_line_tokens(line);
+	let register = |token: &str| {
+		let token = token.to_lowercase();
+		match token.as_str() {
+			"input" | "in" | "i" => "in".to_string(),
+			"output" | "out" | "o" => "out".to_string(),
+			_ => token.trim_start_matches("reg").to_string(),
+		}
+	};
+	let mnemonic = tokens.first().map(|t| t.to_lowercase()).unwrap_or_default();
+	let mut formatted: Vec<String> = tokens
+		.iter()
+		.enumerate()
+		.map(|(i, &token)| {
+			if token.starts_with(['%', '\'', '"']) || token == "->" {
+				return token.to_string();
+			}
+			match mnemonic.as_str() {
+				"mov" | "ldi" if i == 0 => mnemonic.clone(),
+				"mov" => register(token),
+				"ldi" if i == 1 => token.to_lowercase(),
+				"ldi" => register(token),
+				"halt" | "jez" | "jnz" | "jgz" | "jgez" | "jlz" | "jlez" => token.to_lowercase(),
+				_ => token.to_string(),
+			}
+		})
+		.collect();
+	formatted.extend(comment.map(str::to_string));
+	formatted.join(" ")
+}
+
+
+/// Splits a line into its tokens and the comment after them. Tokens are separated by whitespace and `->`, and character
+/// and string literals are one token each.
+fn parameter_line_tokens(line: &str) -> (Vec<&str>, Option<&str>) {
+	let mut tokens = Vec::new();
+	let mut i = 0;
+	while i < line.len() {
+		let rest = &line[i..];
+		let length = if rest.starts_with("//") || rest.starts_with("/*") {
+			return (tokens, Some(rest.trim_end()));
+		} else if rest.starts_with(char::is_whitespace) {
+			i += rest.chars().next().unwrap().len_utf8();
+			continue;
+		} else if rest.starts_with("->") {
+			2
+		} else if rest.starts_with('\'') {
+			char_length(rest)
+		} else if rest.starts_with('"') {
+			string_length(rest)
+		} else {
+			rest.char_indices()
+				.find(|&(j, c)| c.is_whitespace() || ["->", "//", "/*"].iter().any(|s| rest[j..].starts_with(s)))
+				.map_or(rest.len(), |(j, _)| j)
+		};
+		tokens.push(&rest[..length]);
+		i += length;
+	}
+	(tokens, None)
+}
+
+
+/// Writes an instruction in lower case with single spaces. Moves and loads write registers as numbers and the input and
+/// output as `in` and `out`, and strings are kept as they are.
+fn format_instruction(node: Pair<Rule>) -> String {
+	let instruction = node.into_inner().next().unwrap();
+	let register = |operand: Pair<Rule>, io: &str| match operand.clone().into_inner().next() {
+		Some(_) => io.to_string(),
+		None => operand.as_str().trim().chars().last().unwrap().to_string(),
+	};
+	match instruction.as_rule() {
+		Rule::mov => {
+			let mut operands = instruction.into_inner();
+			let from = operands.next().unwrap();
+			let to = operands.next().unwrap();
+			format!("mov {} {}", register(from, "in"), register(to, "out"))
+		}
+		Rule::ldi => {
+			let mut operands = instruction.into_inner();
+			let value = format_byte(&operands.next().unwrap());
+			format!("ldi {value} -> {}", register(operands.next().unwrap(), "out"))
+		}
+		Rule::print => format!("print {}", instruction.into_inner().next().unwrap().as_str()),
+		_ => instruction.as_str().split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase(),
+	}
+}
+
+
+/// Writes a value in the input section or the value of an `ldi`, keeping the case of characters, strings and paths.
+fn format_byte(byte: &Pair<Rule>) -> String {
+	let value = byte.clone().into_inner().next().unwrap();
+	match value.as_rule() {
+		Rule::char_input | Rule::unicode_char | Rule::string => value.as_str().to_string(),
+		Rule::incbin => format!("incbin {}", value.into_inner().next().unwrap().as_str()),
+		_ => value.as_str().to_lowercase(),
+	}
+}
+
+
+/// Writes the items with the comments between them.
+fn write_items(source: &str, items: &[Item]) -> String {
+	let mut lines: Vec<String> = Vec::new();
+	let mut previous_end = 0;
+	for (i, current) in items.iter().enumerate() {
+		write_gap(&source[previous_end..current.span.start], current.indent, i > 0, &mut lines);
+		if current.blank_before && lines.last().is_some_and(|l| !l.is_empty()) {
+			lines.push(String::new());
+		}
+		for line in current.text.lines() {
+			lines.push(format!("{}{line}", INDENT.repeat(current.indent)));
+		}
+		previous_end = current.span.end;
+	}
+	let indent = items.last().map_or(0, |i| i.indent);
+	write_gap(&source[previous_end..], indent, !items.is_empty(), &mut lines);
+	while lines.last().is_some_and(String::is_empty) {
+		lines.pop();
+	}
+	let mut output = lines.join("\n");
+	output.push('\n');
+	output
+}
+
+
+/// Writes the comments in the text between two items. Comments on the same line as the item before them stay on that
+/// line, other comments go on their own line with the indentation of the item after them.
+fn write_gap(gap: &str, indent: usize, after_item: bool, lines: &mut Vec<String>) {
+	let mut newlines = 0;
+	let mut rest = gap;
+	let mut on_item_line = after_item;
+	loop {
+		let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() && c != '\n');
+		if let Some(after) = trimmed.strip_prefix('\n') {
+			newlines += 1;
+			on_item_line = false;
+			rest = after;
+			continue;
+		}
+		let length = if trimmed.starts_with("//") {
+			trimmed.find('\n').unwrap_or(trimmed.len())
+		} else if trimmed.starts_with("/*") {
+			trimmed.find("*/").map_or(trimmed.len(), |v| v + 2)
+		} else {
+			break;
+		};
+		let comment = trimmed[..length].trim_end();
+		if on_item_line {
+			let last = lines.last_mut().unwrap();
+			last.push(' ');
+			last.push_str(comment);
+		} else {
+			push_blank_line(newlines, lines);
+			lines.push(format!("{}{comment}", INDENT.repeat(indent)));
+		}
+		newlines = 0;
+		rest = &trimmed[length..];
+	}
+	push_blank_line(newlines, lines);
+}
+
+
+/// Keeps one empty line where the source had at least one.
+fn push_blank_line(newlines: usize, lines: &mut Vec<String>) {
+	if newlines >= 2 && lines.last().is_some_and(|l| !l.is_empty()) {
+		lines.push(String::new());
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::format;
+	use crate::parser::parse;
+
+
+	const MESSY: &str = "input:
+'H' ,0x10,  -1
+\"hi\\n\"
+program:
+  // A comment before everything.
+    MACRO twice(%value ):
+LDI %value -> OUT
+      ldi %value->o   // Trailing comment.
+  end_macro:
+limit=63
+  label Start:
+twice('a')
+
+
+MOV INPUT 3
+  mov reg3 output /* A block comment. */
+limit
+jGz reg1 reg2 UNSIGNED
+print \"done\\n\"
+  Start
+   halt reg3
+";
+
+
+	/// The example programs, the standard library and a program that is formatted badly on purpose.
+	const EXAMPLES: &[&str] = &[
+		include_str!("../../../hello_world.myvm"),
+		include_str!("../../../interrupts.myvm"),
+		include_str!("../../../print_emoji.myvm"),
+		include_str!("../../../print_nums.myvm"),
+		include_str!("../../../network_example/producer.myvm"),
+		include_str!("../../../network_example/uppercaser.myvm"),
+		include_str!("../std/compare.myvm"),
+		include_str!("../std/math.myvm"),
+		include_str!("../std/print.myvm"),
+		include_str!("../std/registers.myvm"),
+		MESSY,
+	];
+
+
+	#[test]
+	fn formatting_twice_changes_nothing() {
+		for example in EXAMPLES {
+			let formatted = format(example).unwrap();
+			assert_eq!(format(&formatted).unwrap(), formatted);
+		}
+	}
+
+
+	#[test]
+	fn formatting_keeps_the_bytecode() {
+		for example in EXAMPLES {
+			let formatted = format(example).unwrap();
+			let before = parse(example).unwrap();
+			let after = parse(&formatted).unwrap();
+			assert_eq!(after.program(), before.program(), "{formatted}");
+			assert_eq!(after.input(), before.input(), "{formatted}");
+		}
+	}
+
+
+	#[test]
+	fn formats_the_messy_example() {
+		let formatted = format(MESSY).unwrap();
+		assert!(formatted.starts_with("input:\n'H', 0x10, -1\n\"hi\\n\"\n\nprogram:\n// A comment before everything.\n"), "{formatted}");
+		assert!(formatted.contains("\nmacro twice(%value):\n    ldi %value -> out\n    ldi %value -> out // Trailing comment.\nend_macro:\n"), "{formatted}");
+		assert!(formatted.contains("\n    limit = 63\nlabel Start:\n    twice('a')\n\n    mov in 3\n"), "{formatted}");
+		assert!(formatted.contains("\n    mov 3 out /* A block comment. */\n"), "{formatted}");
+		assert!(formatted.contains("\n    jgz reg1 reg2 unsigned\n"), "{formatted}");
+	}
+
+
+	#[test]
+	fn formats_lines_with_parameters() {
+		let source = "program:\nmacro copy(%from, %to, %done, %value):\n  MOV %from   REG4\n  Mov IN %to\nJGZ %from REG2   UNSIGNED\n  LABEL %done :\n  Show( ''' ,%value )\n%done\nend_macro:\n";
+		let formatted = format(source).unwrap();
+		assert_eq!(formatted, "program:\nmacro copy(%from, %to, %done, %value):\n    mov %from 4\n    mov in %to\n    jgz %from reg2 unsigned\n    label %done:\n    Show(''', %value)\n    %done\nend_macro:\n");
+		assert_eq!(format(&formatted).unwrap(), formatted);
+
+		let source = "program:\nmacro load(%value, %to):\n  LDI %value->%to /* The value. */\n  LDI '%' -> REG1\n  ldi 0XFF->o\nend_macro:\n";
+		let formatted = format(source).unwrap();
+		assert_eq!(formatted, "program:\nmacro load(%value, %to):\n    ldi %value -> %to /* The value. */\n    ldi '%' -> 1\n    ldi 0xff -> out\nend_macro:\n");
+	}
+}
@@ -0,0 +1,455 @@
+//! Expands `include "path.myvm"` directives, so macros and code can be shared between programs.
+//!
+//! An included file is a program of its own, with a `program:` line but without an input section. Everything after its
+//! `program:` line replaces the include directive. A file is only ever included once, later includes of it are ignored,
+//! and a file that ends up including itself is an error.
+//!
+//! Files are found and read by a [`Resolver`], so programs can be assembled from files on disk with [`FileResolver`],
+//! or from files that only exist in memory with [`VirtualFileSystem`].
+//!
+//! The standard library is built into the assembler and is included by name, EG `include "std/print"`, whatever the
+//! resolver is. `include "std"` includes all of it.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use color_eyre::eyre::{eyre, Report};
+use color_eyre::Result;
+use pest::iterators::Pair;
+
+use crate::diagnostics::{closest, trimmed_span, SpannedError};
+use crate::lexer::{lex, Rule};
+use crate::preprocessor::source_map::{included_note, IncludedText, RoundMap, SourceMap};
+
+
+/// The files of the standard library, by the name they are included with.
+pub const STANDARD_LIBRARY: &[(&str, &str)] = &[
+	("std", include_str!("../std/std.myvm")),
+	("std/compare", include_str!("../std/compare.myvm")),
+	("std/math", include_str!("../std/math.myvm")),
+	("std/print", include_str!("../std/print.myvm")),
+	("std/registers", include_str!("../std/registers.myvm")),
+];
+
+
+/// Finds and reads the files that programs include.
+pub trait Resolver {
+	/// Finds the file that `path` refers to when it is included from the file named `from`.
+	///
+	/// Returns a name for the file that is the same however the file is included, which is what files are told apart by
+	/// and what includes in that file are resolved relative to.
+	fn locate(&self, from: &str, path: &str) -> Result<String>;
+	/// Reads the file named `name`, as returned by [`Resolver::locate`].
+	fn read(&self, name: &str) -> Result<String>;
+	/// Reads the file named `name` as bytes, for `incbin` in the input section.
+	fn read_bytes(&self, name: &str) -> Result<Vec<u8>> {
+		self.read(name).map(String::into_bytes)
+	}
+}
+
+
+/// Reads included files from disk, relative to the directory of the file that includes them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileResolver;
+
+
+impl Resolver for FileResolver {
+	fn locate(&self, from: &str, path: &str) -> Result<String> {
+		let directory = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+		let path = directory.join(path);
+		match fs::canonicalize(&path) {
+			Ok(v) => Ok(v.display().to_string()),
+			Err(e) => Err(eyre!("Could not find {}: {e}", path.display())),
+		}
+	}
+
+	fn read(&self, name: &str) -> Result<String> {
+		fs::read_to_string(name).map_err(|e| eyre!("Could not read {name}: {e}"))
+	}
+
+	fn read_bytes(&self, name: &str) -> Result<Vec<u8>> {
+		fs::read(name).map_err(|e| eyre!("Could not read {name}: {e}"))
+	}
+}
+
+
+/// Files that only exist in memory, EG the files open in an editor in the browser. Paths are separated with `/`.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualFileSystem {
+	files: HashMap<String, String>,
+}
+
+
+impl VirtualFileSystem {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds the file `name` with the contents `source`, replacing the file if it already exists.
+	pub fn insert(&mut self, name: &str, source: String) {
+		self.files.insert(normalize(Path::new(name)), source);
+	}
+}
+
+
+impl Resolver for VirtualFileSystem {
+	fn locate(&self, from: &str, path: &str) -> Result<String> {
+		let directory = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+		let name = normalize(&directory.join(path));
+		if self.files.contains_key(&name) {
+			Ok(name)
+		} else {
+			Err(eyre!("There is no file called {name}."))
+		}
+	}
+
+	fn read(&self, name: &str) -> Result<String> {
+		self.files.get(name).cloned().ok_or_else(|| eyre!("There is no file called {name}."))
+	}
+}
+
+
+/// Removes the `.` and `..` in a path, without looking at the file system.
+fn normalize(path: &Path) -> String {
+	let mut normalized = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::CurDir => (),
+			Component::ParentDir => {
+				normalized.pop();
+			}
+			Component::RootDir | Component::Prefix(_) => (),
+			Component::Normal(part) => normalized.push(part),
+		}
+	}
+	normalized.to_string_lossy().replace('\\', "/")
+}
+
+
+/// Doesn't find any files, for programs that are not in a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFiles;
+
+
+impl Resolver for NoFiles {
+	fn locate(&self, _from: &str, path: &str) -> Result<String> {
+		Err(eyre!("Can not include {path}, because files can only be included by programs that are in a file."))
+	}
+
+	fn read(&self, name: &str) -> Result<String> {
+		Err(eyre!("Can not read {name}."))
+	}
+}
+
+
+/// Replaces every include directive in `source` with the program of the file it includes. `file_name` is the name of the
+/// file `source` is in, as `resolver` names files.
+///
+/// Errors in included files are reported at the include directive in `source`, with a note that says where they are.
+pub(crate) fn expand_includes(source: &str, file_name: &str, resolver: &dyn Resolver) -> Result<(String, SourceMap)> {
+	let directives = find_includes(lex(source)?);
+	let mut expander = Expander {
+		resolver,
+		included: HashSet::from([file_name.to_string()]),
+		stack: vec![file_name.to_string()],
+		expanded: String::with_capacity(source.len()),
+		pieces: Vec::new(),
+	};
+	let mut round = RoundMap::default();
+	let mut copied_until = 0;
+	for (directive, path) in directives {
+		expander.expanded.push_str(&source[copied_until..directive.start]);
+		round.copied(copied_until..directive.start);
+		let start = expander.expanded.len();
+		expander.include(file_name, &path, &directive)?;
+		round.included(directive.clone(), expander.expanded.len() - start);
+		copied_until = directive.end;
+	}
+	expander.expanded.push_str(&source[copied_until..]);
+	round.copied(copied_until..source.len());
+	Ok((expander.expanded, SourceMap::from_includes(round, expander.pieces)))
+}
+
+
+struct Expander<'a> {
+	resolver: &'a dyn Resolver,
+	/// Every file that has been included, so it is not included again.
+	included: HashSet<String>,
+	/// The files that are being included, from the outermost file in.
+	stack: Vec<String>,
+	expanded: String,
+	pieces: Vec<IncludedText>,
+}
+
+
+impl Expander<'_> {
+	/// Adds the program of the file `path` refers to from the file `from`. `directive` is the include directive in the
+	/// outermost file, where errors are reported.
+	fn include(&mut self, from: &str, path: &str, directive: &Range<usize>) -> Result<()> {
+		let included_from = (self.stack.len() > 1).then(|| format!("\"{path}\" is included by {from}."));
+		let spanned = |message: String| {
+			let error = SpannedError::new(message, directive.clone());
+			match &included_from {
+				Some(note) => error.with_note(note.clone()),
+				None => error,
+			}
+		};
+		let error = |message: String| Report::new(spanned(message));
+		let library = STANDARD_LIBRARY.iter().find(|(name, _)| *name == path).map(|(_, source)| *source);
+		if library.is_none() && path.starts_with("std/") {
+			let error = spanned(format!("The standard library has no file called \"{path}\"."));
+			let names: Vec<&str> = STANDARD_LIBRARY.iter().map(|(name, _)| *name).collect();
+			return Err(Report::new(match closest(path, names.iter().copied()) {
+				Some(name) => error.with_help(format!("Did you mean `{name}`?")),
+				None => error.with_help(format!("The files in it are {}.", names.join(", "))),
+			}));
+		}
+		let name = match library {
+			Some(_) => path.to_string(),
+			None => self.resolver.locate(from, path).map_err(|e| error(format!("Could not include \"{path}\": {e}")))?,
+		};
+		if let Some(start) = self.stack.iter().position(|f| *f == name) {
+			let mut cycle = self.stack[start..].to_vec();
+			cycle.push(name);
+			return Err(Report::new(
+				SpannedError::new(format!("\"{path}\" includes itself."), directive.clone())
+					.with_note(format!("The files include each other like this: {}", cycle.join(" -> "))),
+			));
+		}
+		if !self.included.insert(name.clone()) {
+			return Ok(());
+		}
+		let source: Rc<str> = match library {
+			Some(source) => source.into(),
+			None => self.resolver.read(&name).map_err(|e| error(format!("Could not include \"{path}\": {e}")))?.into(),
+		};
+		let file: Rc<str> = name.as_str().into();
+		let in_file = |e: SpannedError| {
+			let note = included_note(&file, &source, e.span.start);
+			Report::new(SpannedError::new(e.message, directive.clone()).with_note(note))
+		};
+		let lexed = lex(&source).map_err(|e| match e.downcast::<SpannedError>() {
+			Ok(e) => in_file(e),
+			Err(e) => e,
+		})?;
+		let mut nodes = lexed.clone().into_inner();
+		let inputs = nodes.next().unwrap();
+		if !inputs.as_str().trim().is_empty() {
+			let span = trimmed_span(&inputs);
+			return Err(in_file(SpannedError::new(format!("The included file {name} has an input section."), span)));
+		}
+		let program_start = nodes.next().unwrap().into_inner().next().unwrap().as_span().end();
+		let directives = find_includes(lexed);
+
+		self.stack.push(name.clone());
+		let mut copied_until = program_start;
+		for (nested, nested_path) in directives {
+			self.copy(&file, &source, copied_until..nested.start);
+			self.include(&name, &nested_path, directive)?;
+			copied_until = nested.end;
+		}
+		self.copy(&file, &source, copied_until..source.len());
+		self.stack.pop();
+		Ok(())
+	}
+
+	/// Copies the text at `range` in an included file. It goes on lines of its own, so it does not run into the text
+	/// around the directive.
+	fn copy(&mut self, file: &Rc<str>, source: &Rc<str>, range: Range<usize>) {
+		self.expanded.push('\n');
+		let start = self.expanded.len();
+		self.expanded.push_str(&source[range.clone()]);
+		self.pieces.push(IncludedText {
+			expanded: start..self.expanded.len(),
+			file: file.clone(),
+			source: source.clone(),
+			start: range.start,
+		});
+		self.expanded.push('\n');
+	}
+}
+
+
+/// Every include directive in a file, as the span of the directive and the path it includes.
+fn find_includes(file: Pair<Rule>) -> Vec<(Range<usize>, String)> {
+	let program = file.into_inner().find(|p| p.as_rule() == Rule::program).unwrap();
+	program
+		.into_inner()
+		.filter(|a| a.as_rule() == Rule::action)
+		.map(|a| a.into_inner().next().unwrap())
+		.filter(|n| n.as_rule() == Rule::include)
+		.map(|n| {
+			let path = n.clone().into_inner().next().unwrap();
+			let quoted = path.as_str();
+			(trimmed_span(&n).start..path.as_span().end(), quoted[1..quoted.len() - 1].to_string())
+		})
+		.collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+	use bytecode_interpreter::run::{Extension, Input, Machine, Output, Queue};
+
+	use super::VirtualFileSystem;
+	use crate::diagnostics::{diagnose_with, Diagnostic};
+	use crate::parser::{parse_with, ParseOptions, ScratchPolicy};
+
+
+	const MAIN: &str = "program:\nnop\ninclude \"lib.myvm\"\nhalt\n";
+
+
+	/// Assembles [`MAIN`], which includes `lib` as lib.myvm and `deep` as deep.myvm.
+	fn diagnose_main(lib: &str, deep: &str) -> Vec<Diagnostic> {
+		let mut files = VirtualFileSystem::new();
+		files.insert("main.myvm", MAIN.to_string());
+		files.insert("lib.myvm", lib.to_string());
+		files.insert("deep.myvm", deep.to_string());
+		diagnose_with(MAIN, &ParseOptions { file_name: "main.myvm".to_string(), resolver: &files, ..Default::default() })
+	}
+
+
+	#[test]
+	fn errors_in_included_files_point_at_the_directive() {
+		let directive = MAIN.find("include").unwrap()..MAIN.find("\nhalt").unwrap();
+		let cases = [
+			("program:\nnop\nnop\nmov 9 9\n", "program:\n", "`lib.myvm` at line 4, column 1"),
+			("program:\nnop\nnop\nbogus\n", "program:\n", "`lib.myvm` at line 4, column 1"),
+			("program:\nmacro m(%a):\nmov %a 9\nend_macro:\nm(1)\n", "program:\n", "`lib.myvm` at line 5, column 1"),
+			("program:\nnop\ninclude \"deep.myvm\"\n", "program:\nnop\nbogus\n", "`deep.myvm` at line 3, column 1"),
+			("input:\n1\nprogram:\n", "program:\n", "`lib.myvm` at line 1, column 1"),
+		];
+		for (lib, deep, note) in cases {
+			let diagnostics = diagnose_main(lib, deep);
+			assert_eq!(diagnostics.len(), 1, "{lib}");
+			assert_eq!(diagnostics[0].span, directive, "{lib}");
+			assert!(diagnostics[0].notes.iter().any(|n| n.contains(note)), "{lib}: {:?}", diagnostics[0].notes);
+			assert!(diagnostics[0].render(MAIN, "main.myvm").contains("3 | include \"lib.myvm\"\n"), "{lib}");
+		}
+	}
+
+
+	/// Assembles `source`, which is put after a `program:` line that includes `library` from the standard library, and
+	/// runs it. Returns the registers and the output. The labels of the library can end up above 63, so they are loaded
+	/// with [`ScratchPolicy::KeepReg3`].
+	fn run_with_library(library: &str, source: &str) -> ([u8; 6], Vec<u8>) {
+		run_with_scratch(&format!("program:\ninclude \"{library}\"\n{source}\n"), ScratchPolicy::KeepReg3)
+	}
+
+
+	/// Like [`run_with_library`], with 64 `nop`s before `source` so every label the library loads is above 63.
+	fn run_far_with_library(library: &str, source: &str, scratch: ScratchPolicy) -> ([u8; 6], Vec<u8>) {
+		let padding = "nop\n".repeat(64);
+		run_with_scratch(&format!("program:\ninclude \"{library}\"\n{padding}{source}\n"), scratch)
+	}
+
+
+	fn run_with_scratch(source: &str, scratch: ScratchPolicy) -> ([u8; 6], Vec<u8>) {
+		let parsed = parse_with(source, &ParseOptions { scratch, ..Default::default() }).unwrap();
+		let output = Queue::default();
+		let program = parsed.program().to_vec();
+		let mut machine = Machine::new_owned(program, Input::ARRAY(&[]), Output::QUEUE(output.clone()), Extension::NONE);
+		machine.run().unwrap();
+		(machine.registers(), output.take().into())
+	}
+
+
+	const SAMPLES: [u8; 16] = [0, 1, 2, 3, 7, 9, 10, 16, 99, 100, 101, 127, 128, 129, 200, 255];
+
+
+	#[test]
+	fn multiply() {
+		for a in SAMPLES {
+			for b in SAMPLES {
+				let (registers, _) = run_with_library("std/math", &format!("ldi {a} -> 4\nldi {b} -> 5\nmultiply()"));
+				assert_eq!(registers[4], a.wrapping_mul(b), "{a} * {b}");
+			}
+		}
+	}
+
+
+	#[test]
+	fn divide_by() {
+		for divisor in [1, 2, 3, 7, 10, 100, 127, 128] {
+			for dividend in SAMPLES.into_iter().chain([divisor - 1, divisor, divisor + 1, 255 / divisor * divisor]) {
+				let (registers, _) = run_with_library("std/math", &format!("ldi {dividend} -> 4\ndivide_by({divisor})"));
+				assert_eq!(registers[4], dividend / divisor, "{dividend} / {divisor}");
+				assert_eq!(registers[5], dividend % divisor, "{dividend} % {divisor}");
+			}
+		}
+	}
+
+
+	#[test]
+	fn print_decimal() {
+		for value in SAMPLES.into_iter().chain([11, 19, 90, 109, 110, 190]) {
+			let (_, output) = run_with_library("std/print", &format!("ldi {value} -> 4\nprint_decimal()"));
+			assert_eq!(String::from_utf8(output).unwrap(), value.to_string());
+		}
+	}
+
+
+	#[test]
+	fn compare_jumps() {
+		let pairs = [(0, 0), (1, 2), (2, 1), (100, 200), (200, 100), (255, 254), (127, 128), (128, 127), (0, 127), (255, 255)];
+		for (a, b) in pairs {
+			let jumps: [(&str, bool); 4] =
+				[("jump_if_equal", a == b), ("jump_if_not_equal", a != b), ("jump_if_less", a < b), ("jump_if_greater", a > b)];
+			for (jump, taken) in jumps {
+				let source = format!("ldi {a} -> 4\nldi {b} -> 5\n{jump}(yes)\nprint \"n\"\nhalt\nlabel yes:\nprint \"y\"");
+				let (_, output) = run_with_library("std/compare", &source);
+				assert_eq!(output, [if taken { b'y' } else { b'n' }], "{jump} with {a} and {b}");
+			}
+		}
+	}
+
+
+	#[test]
+	fn math_above_63() {
+		for scratch in [ScratchPolicy::KeepReg3, ScratchPolicy::Clobber] {
+			for (a, b) in [(0u8, 5u8), (5, 0), (7, 9), (200, 3), (16, 16)] {
+				let (registers, _) = run_far_with_library("std/math", &format!("ldi {a} -> 4\nldi {b} -> 5\nmultiply()"), scratch);
+				assert_eq!(registers[4], a.wrapping_mul(b), "{a} * {b} with {scratch:?}");
+			}
+			for (dividend, divisor) in [(0u8, 10u8), (99, 10), (200, 7), (255, 128), (127, 1)] {
+				let (registers, _) = run_far_with_library("std/math", &format!("ldi {dividend} -> 4\ndivide_by({divisor})"), scratch);
+				assert_eq!(registers[4], dividend / divisor, "{dividend} / {divisor} with {scratch:?}");
+				assert_eq!(registers[5], dividend % divisor, "{dividend} % {divisor} with {scratch:?}");
+			}
+		}
+	}
+
+
+	#[test]
+	fn compare_jumps_above_63() {
+		for scratch in [ScratchPolicy::KeepReg3, ScratchPolicy::Clobber] {
+			for (a, b) in [(0, 0), (1, 2), (2, 1), (200, 100), (255, 255)] {
+				let jumps: [(&str, bool); 4] =
+					[("jump_if_equal", a == b), ("jump_if_not_equal", a != b), ("jump_if_less", a < b), ("jump_if_greater", a > b)];
+				for (jump, taken) in jumps {
+					let source = format!("ldi {a} -> 4\nldi {b} -> 5\n{jump}(yes)\nprint \"n\"\nhalt\nlabel yes:\nprint \"y\"");
+					let (_, output) = run_far_with_library("std/compare", &source, scratch);
+					assert_eq!(output, [if taken { b'y' } else { b'n' }], "{jump} with {a} and {b} with {scratch:?}");
+				}
+			}
+		}
+	}
+
+
+	#[test]
+	fn registers() {
+		let (registers, _) = run_with_library("std/registers", "ldi 1 -> 4\nldi 2 -> 5\nswap(4, 5, 1)");
+		assert_eq!([registers[1], registers[4], registers[5]], [1, 2, 1]);
+		let (registers, _) = run_with_library("std/registers", "ldi 9 -> 4\ncopy(4, 2)\nzero(4)");
+		assert_eq!([registers[2], registers[4]], [9, 0]);
+	}
+
+
+	#[test]
+	fn print_characters() {
+		let (_, output) = run_with_library("std/print", "print_char('A')\nprint_char(200)\nprint_newline()");
+		assert_eq!(output, [b'A', 200, b'\n']);
+	}
+}
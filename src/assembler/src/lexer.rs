@@ -1,9 +1,10 @@
-use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
 
+use crate::diagnostics::SpannedError;
+
 
 #[derive(Parser)]
 #[grammar = "./grammar.pest"] // relative to src
@@ -12,5 +13,5 @@ struct Grammar;
 
 pub fn lex(program: &str) -> Result<Pair<Rule>> {
 	let lexed = Grammar::parse(Rule::file, program);
-	Ok(lexed.map_err(|x| eyre!(x))?.into_iter().next().unwrap())
+	Ok(lexed.map_err(SpannedError::from_pest)?.into_iter().next().unwrap())
 }
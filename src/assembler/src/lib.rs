@@ -1,3 +1,10 @@
+pub mod diagnostics;
+pub mod export;
+pub mod formatter;
+pub mod include;
 pub mod lexer;
 pub mod parser;
 mod preprocessor;
+pub mod symbols;
+pub mod turing_complete;
+pub mod verilog;
@@ -1,16 +1,19 @@
 use std::collections::HashMap;
+use std::ops::Range;
 
 use bytecode_interpreter::run::{
-    Arithmetic, ARITHMETIC_PREFIX, Conditional, CONDITIONAL_PREFIX, FromStore, LITERAL_PREFIX,
-    MOVE_PREFIX, ToStore,
+    Arithmetic, ARITHMETIC_PREFIX, Conditional, CONDITIONAL_PREFIX, ConditionalSource,
+    ExtendedArithmetic, Extension, FromStore, Halt, Interrupt, LITERAL_PREFIX, MOVE_PREFIX, ToStore,
 };
-use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use pest::iterators::{Pair, Pairs};
 
+use crate::diagnostics::{closest, trimmed_span, Diagnostic, ErrorList, LineColumn, SpannedError};
+use crate::include::{NoFiles, Resolver};
 use crate::lexer::lex;
 use crate::lexer::Rule;
 use crate::preprocessor::preprocess;
+use crate::preprocessor::source_map::SourceMap;
 
 
 #[derive(Debug)]
@@ -18,58 +21,265 @@ pub struct SuccessfulParse {
 	pub(crate) input: Vec<u8>,
 	pub(crate) program: Vec<u8>,
 	pub(crate) expanded: String,
+	/// Every label and the position in the program it points to, sorted by position.
+	pub(crate) labels: Vec<(String, u8)>,
+	/// The line in the source every instruction came from. Instructions from macros get the line of the macro call.
+	pub(crate) source_lines: Vec<u32>,
+	/// The extensions the instructions of the program need, see [`Extension`].
+	pub(crate) extensions: u8,
+	/// Problems that do not stop the program from assembling, pointing at the source the user wrote.
+	pub(crate) warnings: Vec<Diagnostic>,
 }
 
 
 impl SuccessfulParse {
 	pub fn from(input: Vec<u8>, program: Vec<u8>, expanded: String) -> Self {
 		Self {
+			extensions: Extension::required_by_program(&program),
 			input,
 			program,
 			expanded,
+			labels: Vec::new(),
+			source_lines: Vec::new(),
+			warnings: Vec::new(),
 		}
 	}
 	#[allow(dead_code)]
 	pub fn into_raw_parts(self) -> (Vec<u8>, Vec<u8>, String) {
 		(self.input, self.program, self.expanded)
 	}
+	pub fn input(&self) -> &[u8] {
+		&self.input
+	}
+	pub fn program(&self) -> &[u8] {
+		&self.program
+	}
+	pub fn labels(&self) -> &[(String, u8)] {
+		&self.labels
+	}
+	pub fn source_lines(&self) -> &[u32] {
+		&self.source_lines
+	}
+	/// The extensions the machine needs to run the program, or'ed together like [`Extension`] does.
+	pub fn extensions(&self) -> u8 {
+		self.extensions
+	}
+	pub fn warnings(&self) -> &[Diagnostic] {
+		&self.warnings
+	}
 }
 
 
-pub fn parse(program: &str) -> Result<SuccessfulParse> {
-	let program = preprocess(program)?;
+/// How many errors are reported at most, since every error after a syntax error could be caused by the ones before it.
+const MAX_ERRORS: usize = 20;
+
+
+/// The mnemonics of every instruction, to suggest when an identifier is not known.
+const MNEMONICS: &[&str] = &[
+	"nop", "ldi", "print", "j", "jez", "jnz", "jgez", "jgz", "jlez", "jlz", "halt", "mov", "add", "sub", "or", "nor", "xor", "xnor", "and",
+	"nand", "shl", "shr", "asr", "rol", "ror", "mul", "mulh", "inc", "dec", "imask", "ivec", "itimer", "reti", "icause",
+];
+
+
+/// Which registers the code that loads a label above 63 may overwrite.
+///
+/// A literal only holds 6 bits, so the address of such a label is built in reg0 with arithmetic, which always reads reg1
+/// and reg2 and writes reg3. Overwriting registers behind the back of the program is surprising, so programs have to opt
+/// in, and every load that does it is a warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScratchPolicy {
+	/// reg1, reg2 and reg3 are overwritten, so a conditional jump to the label tests the address instead of reg3.
+	Clobber,
+	/// reg3 is copied to reg5 first and back afterwards, so conditional jumps to the label still test reg3. reg1, reg2 and
+	/// reg5 are overwritten.
+	KeepReg3,
+	/// Labels above 63 are an error.
+	#[default]
+	Forbid,
+}
+
+
+/// How a program is assembled.
+pub struct ParseOptions<'a> {
+	pub scratch: ScratchPolicy,
+	/// The name of the file the source is in, as `resolver` names files. Includes are resolved relative to it.
+	pub file_name: String,
+	/// Finds the files the program includes.
+	pub resolver: &'a dyn Resolver,
+}
+
+
+impl Default for ParseOptions<'_> {
+	/// Options for a program that is not in a file, so it can not include other files.
+	fn default() -> Self {
+		Self {
+			scratch: ScratchPolicy::default(),
+			file_name: String::new(),
+			resolver: &NoFiles,
+		}
+	}
+}
+
+
+/// Assembles `source` with the default [`ParseOptions`].
+pub fn parse(source: &str) -> Result<SuccessfulParse> {
+	parse_with(source, &ParseOptions::default())
+}
+
+
+/// Assembles `source`. If it does not assemble, the error holds every problem that was found.
+///
+/// After a syntax error the line it is on is blanked out and the source is assembled again, so the errors on other lines are found too.
+pub fn parse_with(source: &str, options: &ParseOptions) -> Result<SuccessfulParse> {
+	let mut errors: Vec<SpannedError> = Vec::new();
+	let mut recovered = source.to_string();
+	while errors.len() < MAX_ERRORS {
+		let error = match preprocess(&recovered, &options.file_name, options.resolver) {
+			Ok((program, source_map)) => match parse_expanded(&recovered, program, &source_map, options) {
+				Ok(parsed) if errors.is_empty() => return Ok(parsed),
+				Ok(_) => break,
+				// Everything after lexing is checked in one go, so there is nothing left to recover from.
+				Err(e) => {
+					match ErrorList::into_errors(source_map.remap(e)) {
+						Ok(found) => errors.extend(found),
+						Err(e) if errors.is_empty() => return Err(e),
+						Err(_) => (),
+					}
+					break;
+				}
+			},
+			Err(e) => match e.downcast::<ErrorList>() {
+				Ok(list) => {
+					errors.extend(list.errors);
+					break;
+				}
+				Err(e) => e,
+			},
+		};
+		let error = match error.downcast::<SpannedError>() {
+			Ok(e) => e,
+			Err(e) if errors.is_empty() => return Err(e),
+			// Errors without a location are probably caused by blanking out lines, so they are not worth reporting.
+			Err(_) => break,
+		};
+		let blanked = blank_lines(&recovered, &error.span);
+		errors.push(error);
+		if blanked == recovered {
+			break;
+		}
+		recovered = blanked;
+	}
+	errors.dedup_by(|a, b| a.span == b.span && a.message == b.message);
+	Err(ErrorList::report(errors))
+}
+
+
+/// Replaces the lines `span` is on with spaces, keeping every byte where it was. A macro definition is blanked out up to its end.
+fn blank_lines(source: &str, span: &Range<usize>) -> String {
+	let start = source[..span.start.min(source.len())].rfind('\n').map_or(0, |v| v + 1);
+	let mut end = source[span.end.max(start).min(source.len())..].find('\n').map_or(source.len(), |v| span.end.max(start) + v);
+	if source[start..end].trim_start().to_lowercase().starts_with("macro") {
+		if let Some(v) = source[end..].find("end_macro:") {
+			end = source[end + v..].find('\n').map_or(source.len(), |w| end + v + w);
+		}
+	}
+	let mut blanked = source[..start].to_string();
+	for c in source[start..end].chars() {
+		match c {
+			'\n' | '\r' => blanked.push(c),
+			_ => blanked.extend(std::iter::repeat_n(' ', c.len_utf8())),
+		}
+	}
+	// The input section needs at least one byte, so a bad byte becomes a 0 instead of nothing.
+	let before = source[..start].to_lowercase();
+	if before.contains("input:") && !before.contains("program:") && !source[start..end].trim().is_empty() {
+		blanked.replace_range(start..start + 1, "0");
+	}
+	blanked.push_str(&source[end..]);
+	blanked
+}
+
+
+/// Parses the source after macros have been expanded. Errors point at the expanded source.
+fn parse_expanded(source: &str, program: String, source_map: &SourceMap, options: &ParseOptions) -> Result<SuccessfulParse> {
+	let scratch = options.scratch;
 	let file: Pair<Rule> = lex(&program)?;
 	let mut instructions = Vec::new();
+	let mut source_lines = Vec::new();
 	let tree = file.into_inner();
-	let (input, mut tree) = parse_input(tree);
+	let mut errors = Vec::new();
+	let mut warnings = Vec::new();
+	let (input, mut tree) = parse_input(tree, options, &mut errors);
 	let mut actions = tree.next().unwrap().into_inner();
 	let start_of_program = actions.next().unwrap();
 	assert_eq!(start_of_program.as_rule(), Rule::start_of_program);
-	let label_positions = find_labels(actions.clone());
+	errors.extend(duplicate_labels(actions.clone(), source, source_map));
+	let (label_positions, load_lengths) = layout_labels(actions.clone(), scratch);
+	let mut load_lengths = load_lengths.into_iter();
+	let definitions = actions.clone();
 	for node in actions {
 		match node.as_rule() {
 			Rule::action => {
 				let node = node.into_inner().next().unwrap();
+				let span = trimmed_span(&node);
+				let line = LineColumn::of(source, source_map.to_original(span.clone()).start).line as u32;
 				match node.as_rule() {
 					Rule::instruction => {
-						let parsed = parse_instruction(node.into_inner().next().unwrap());
-						instructions.push(parsed);
+						let instruction = node.into_inner().next().unwrap();
+						let parsed = match instruction.as_rule() {
+							Rule::ldi => load_immediate(instruction),
+							Rule::print => print_bytes(&parse_string(instruction.into_inner().next().unwrap())),
+							_ => vec![parse_instruction(instruction)],
+						};
+						source_lines.extend(std::iter::repeat_n(line, parsed.len()));
+						instructions.extend(parsed);
 					}
 					Rule::label => (),
 					Rule::macro_call => (),
 					Rule::full_macro => (),
 					Rule::empty => (),
 					Rule::constant => (),
+					// Includes are expanded before macros, so this one came out of a macro.
+					Rule::include => {
+						errors.push(
+							SpannedError::new("Files can not be included by macros.".to_string(), span)
+								.with_help("Include the file at the top of the program instead.".to_string()),
+						);
+					}
 					Rule::use_label_or_const => {
-						let val = label_positions.get(node.as_str().trim());
+						let name = node.as_str().trim();
+						let length = load_lengths.next().unwrap();
+						let val = label_positions.get(name);
 						if val == None {
-							return Err(eyre!("Unknown identifier: {}", node.as_str().trim()));
+							errors.push(unknown_identifier(name, span, &label_positions, &definitions));
+							continue;
 						}
 						let val = *val.unwrap();
+						if val > u8::MAX as usize {
+							errors.push(
+								SpannedError::new(format!("The label `{name}` is at {val}, past the end of the program memory."), span)
+									.with_note("Programs can be at most 256 bytes long.".to_string()),
+							);
+							continue;
+						}
+						if val > 63 && scratch == ScratchPolicy::Forbid {
+							errors.push(
+								SpannedError::new("You tried to use a label with a value greater than 63 which is not supported.".to_string(), span)
+									.with_note(format!("The label `{name}` is at {val}, but literals can only hold values up to 63."))
+									.with_help("Move the code at the label closer to the start of the program, or pick a scratch register policy that lets the assembler overwrite registers to load it.".to_string()),
+							);
+							continue;
+						}
 						if val > 63 {
-							return Err(eyre!("You tried to use a label with a value greater than 63 which is not supported."));
+							let overwritten = if scratch == ScratchPolicy::KeepReg3 { "reg1, reg2 and reg5" } else { "reg1, reg2 and reg3" };
+							warnings.push(
+								SpannedError::new(format!("Loading the label `{name}` overwrites {overwritten}."), span.clone())
+									.with_note(format!("The label is at {val}, so its address is built with arithmetic, because literals can only hold values up to 63.")),
+							);
 						}
-						instructions.push((LITERAL_PREFIX << 6) | val);
+						let load = load_label(val as u8, scratch, length);
+						source_lines.extend(std::iter::repeat_n(line, load.len()));
+						instructions.extend(load);
 					}
 					_ => unreachable!(),
 				}
@@ -78,16 +288,120 @@ pub fn parse(program: &str) -> Result<SuccessfulParse> {
 			_ => unreachable!(),
 		}
 	}
-	Ok(SuccessfulParse::from(input, instructions, program))
+	if !errors.is_empty() {
+		return Err(ErrorList::report(errors));
+	}
+	let mut labels: Vec<(String, u8)> =
+		label_positions.iter().filter_map(|(k, v)| Some((k.to_string(), u8::try_from(*v).ok()?))).collect();
+	labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+	let mut parsed = SuccessfulParse::from(input, instructions, program);
+	parsed.labels = labels;
+	parsed.source_lines = source_lines;
+	parsed.warnings = warnings.into_iter().map(|w| source_map.remap_error(w).to_warning()).collect();
+	Ok(parsed)
+}
+
+
+type LabelPositions<'a> = HashMap<&'a str, usize>;
+
+
+/// An error for an identifier that is not a label, with a suggestion if it looks like a typo of a name that exists.
+fn unknown_identifier(name: &str, span: Range<usize>, labels: &LabelPositions, actions: &Pairs<Rule>) -> SpannedError {
+	let error = SpannedError::new(format!("Unknown identifier: {name}"), span);
+	let mut macros = Vec::new();
+	let mut constants = Vec::new();
+	for action in actions.clone().filter(|a| a.as_rule() == Rule::action) {
+		let node = action.into_inner().next().unwrap();
+		match node.as_rule() {
+			Rule::full_macro => macros.push(node.into_inner().next().unwrap().into_inner().next().unwrap().as_str().trim()),
+			Rule::constant => constants.push(node.into_inner().next().unwrap().as_str().trim()),
+			_ => (),
+		}
+	}
+	if macros.contains(&name) {
+		return error.with_help(format!("`{name}` is a macro, call it with `{name}()`."));
+	}
+	if name.eq_ignore_ascii_case("label") {
+		return error.with_help("Labels are defined with `label NAME:`, with a colon at the end.".to_string());
+	}
+	if name.eq_ignore_ascii_case("mov") {
+		return error.with_help("`mov` needs a source and a target, EG `mov 0 1` or `mov in out`. The registers go from 0 to 5.".to_string());
+	}
+	let candidates = labels.keys().copied().chain(macros).chain(constants).chain(MNEMONICS.iter().copied());
+	match closest(name, candidates) {
+		Some(suggestion) => error.with_help(format!("Did you mean `{suggestion}`?")),
+		None => error.with_help("Define it with `label NAME:` or `NAME = VALUE`.".to_string()),
+	}
+}
+
+
+/// An error for every label that is defined again after its first definition.
+fn duplicate_labels(actions: Pairs<Rule>, source: &str, source_map: &SourceMap) -> Vec<SpannedError> {
+	let mut first_definitions: HashMap<&str, Range<usize>> = HashMap::new();
+	let mut errors = Vec::new();
+	for label in actions.filter(|a| a.as_rule() == Rule::action).map(|a| a.into_inner().next().unwrap()) {
+		if label.as_rule() != Rule::label {
+			continue;
+		}
+		let name = label.clone().into_inner().next().unwrap().as_str().trim();
+		let span = trimmed_span(&label);
+		match first_definitions.get(name) {
+			Some(first) => {
+				let line = LineColumn::of(source, source_map.to_original(first.clone()).start).line;
+				errors.push(
+					SpannedError::new(format!("The label `{name}` is defined more than once."), span)
+						.with_note(format!("It is first defined on line {line}."))
+						.with_help("Rename one of them. Labels defined inside a macro are already renamed for every call.".to_string()),
+				);
+			}
+			None => {
+				first_definitions.insert(name, span);
+			}
+		}
+	}
+	errors
 }
 
 
-type LabelPositions<'a> = HashMap<&'a str, u8>;
+/// Finds where every label is, and how many bytes each use of a label takes, in the order they are used.
+///
+/// Loading a label above 63 takes more than one byte, which moves the labels after it, which can make more loads longer.
+/// So the layout is repeated until nothing moves. Loads never get shorter, they are padded with `nop` instead, so this
+/// always ends.
+fn layout_labels(actions: Pairs<Rule>, scratch: ScratchPolicy) -> (LabelPositions, Vec<usize>) {
+	let uses: Vec<&str> = actions
+		.clone()
+		.filter(|a| a.as_rule() == Rule::action)
+		.map(|a| a.into_inner().next().unwrap())
+		.filter(|n| n.as_rule() == Rule::use_label_or_const)
+		.map(|n| n.as_str().trim())
+		.collect();
+	let mut lengths = vec![1; uses.len()];
+	loop {
+		let positions = find_labels(actions.clone(), &lengths);
+		let mut moved = false;
+		for (length, name) in lengths.iter_mut().zip(&uses) {
+			let needed = match positions.get(name) {
+				Some(&position) if position <= u8::MAX as usize => label_load(position as u8, scratch).len(),
+				_ => 1,
+			};
+			if needed > *length {
+				*length = needed;
+				moved = true;
+			}
+		}
+		if !moved {
+			return (positions, lengths);
+		}
+	}
+}
 
 
-fn find_labels(tree: Pairs<Rule>) -> LabelPositions {
+/// Where every label is, when the uses of labels take `load_lengths` bytes.
+fn find_labels<'a>(tree: Pairs<'a, Rule>, load_lengths: &[usize]) -> LabelPositions<'a> {
 	let mut positions = HashMap::new();
 	let mut number_of_instructions = 0;
+	let mut load_lengths = load_lengths.iter();
 	for node in tree {
 		match node.as_rule() {
 			Rule::action => {
@@ -98,12 +412,13 @@ fn find_labels(tree: Pairs<Rule>) -> LabelPositions {
 						let as_str = ident.as_str().trim();
 						positions.insert(as_str, number_of_instructions);
 					}
-					Rule::instruction => number_of_instructions += 1,
-					Rule::use_label_or_const => number_of_instructions += 1,
+					Rule::instruction => number_of_instructions += instruction_length(node),
+					Rule::use_label_or_const => number_of_instructions += load_lengths.next().unwrap(),
 					Rule::macro_call => (),
 					Rule::full_macro => (),
 					Rule::empty => (),
 					Rule::constant => (),
+					Rule::include => (),
 					_ => unreachable!(),
 				}
 			}
@@ -117,63 +432,301 @@ fn find_labels(tree: Pairs<Rule>) -> LabelPositions {
 }
 
 
-fn parse_input(mut tree: Pairs<Rule>) -> (Vec<u8>, Pairs<Rule>) {
-	let mut input = Vec::new();
-	if tree.peek().unwrap().as_rule() == Rule::inputs {
-		let mut inputs = tree.next().unwrap().into_inner();
-		loop {
-			let next = inputs.next();
-			if next == None {
-				break;
-			}
-			let next = next.unwrap();
-			if next.as_rule() != Rule::input_byte {
-				break;
+/// How many bytes an instruction assembles to. Only `ldi` and `print` take more than one.
+fn instruction_length(instruction: Pair<Rule>) -> usize {
+	let instruction = instruction.into_inner().next().unwrap();
+	match instruction.as_rule() {
+		Rule::ldi => load_immediate(instruction).len(),
+		Rule::print => print_bytes(&parse_string(instruction.into_inner().next().unwrap())).len(),
+		_ => 1,
+	}
+}
+
+
+/// Assembles `ldi VALUE -> TARGET`.
+///
+/// Values up to 63 are loaded with a literal, which overwrites reg0. Larger values are built with arithmetic, which
+/// overwrites reg0, reg1, reg2 and reg3. The target is overwritten last, so it can be any of them.
+fn load_immediate(ldi: Pair<Rule>) -> Vec<u8> {
+	let mut inner = ldi.into_inner();
+	let value = parse_byte(inner.next().unwrap().into_inner().next().unwrap());
+	let target = parse_target(inner.next().unwrap().as_str().trim());
+	if value <= 63 {
+		let mut load = vec![load_literal(value)];
+		if target != 0 {
+			load.push(move_between(0, target));
+		}
+		return load;
+	}
+	let mut load = build_in_reg3(value);
+	if target != 3 {
+		load.push(move_between(3, target));
+	}
+	load
+}
+
+
+/// The values of reg0 to reg3 that are known while assembling a `print`.
+type KnownRegisters = [Option<u8>; 4];
+
+
+/// Assembles `print "STRING"`, which writes `bytes` to the output.
+///
+/// Each byte is made in whichever way takes the fewest instructions, given what is left in reg0 to reg3 from the bytes
+/// before it: moving a register that already holds it, a literal, adding or subtracting a small difference from a byte
+/// that is already in a register, or building it from scratch like `ldi` does.
+fn print_bytes(bytes: &[u8]) -> Vec<u8> {
+	let mut known = [None; 4];
+	let mut code = Vec::new();
+	for &byte in bytes {
+		let best = byte_candidates(byte, &known).into_iter().min_by_key(Vec::len).unwrap();
+		simulate(&mut known, &best);
+		code.extend(best);
+	}
+	code
+}
+
+
+/// Every way of writing `byte` to the output that is worth trying.
+fn byte_candidates(byte: u8, known: &KnownRegisters) -> Vec<Vec<u8>> {
+	let mov = move_between;
+	let out = ToStore::OUT;
+	let mut candidates = Vec::new();
+	if let Some(register) = known.iter().position(|&v| v == Some(byte)) {
+		candidates.push(vec![mov(register as u8, out)]);
+	}
+	if byte <= 63 {
+		candidates.push(vec![load_literal(byte), mov(0, out)]);
+	} else {
+		let mut build = build_in_reg3(byte);
+		build.push(mov(3, out));
+		candidates.push(build);
+	}
+	for base in known.iter().flatten() {
+		let add = ARITHMETIC_PREFIX << 6 | Arithmetic::ADD;
+		let sub = ARITHMETIC_PREFIX << 6 | Arithmetic::SUB;
+		let difference = byte.wrapping_sub(*base);
+		let operands = [(*base, difference, add), (difference, *base, add), (*base, base.wrapping_sub(byte), sub)];
+		for (first, second, operation) in operands {
+			let mut state = *known;
+			let mut code = Vec::new();
+			if place(first, 1, &mut state, &mut code) && place(second, 2, &mut state, &mut code) {
+				code.extend([operation, mov(3, out)]);
+				candidates.push(code);
 			}
-			let inner = next.into_inner().next().unwrap();
-			input.push(match inner.as_rule() {
-				Rule::char_input => {
-					let as_str = inner.as_str().trim();
-					let l = as_str.len();
-					let contents = &as_str[1..l - 1];
-					if contents.len() == 1 {
-						contents.chars().next().unwrap() as u32 as u8
-					} else if contents.len() == 2 {
-						let mut c = contents.chars();
-						assert_eq!(c.next().unwrap(), '\\');
-						match c.next().unwrap() {
-							'r' => b'\r',
-							'n' => b'\n',
-							't' => b'\t',
-							'0' => b'\0',
-							_ => unreachable!(),
-						}
-					} else {
-						unreachable!()
-					}
-				}
-				Rule::dec_input => {
-					let as_str = inner.as_str().trim();
-					as_str.parse::<u8>().unwrap()
-				}
-				Rule::bin_input => {
-					let as_str = inner.as_str().trim();
-					let without_prefix = &as_str[2..];
-					u8::from_str_radix(without_prefix, 2).unwrap()
+		}
+	}
+	candidates
+}
+
+
+/// Adds the code that puts `value` in `register` to `code`, if it can be done with a move or a literal.
+fn place(value: u8, register: u8, known: &mut KnownRegisters, code: &mut Vec<u8>) -> bool {
+	let load = if known[register as usize] == Some(value) {
+		vec![]
+	} else if let Some(from) = known.iter().position(|&v| v == Some(value)) {
+		vec![move_between(from as u8, register)]
+	} else if value <= 63 {
+		vec![load_literal(value), move_between(0, register)]
+	} else {
+		return false;
+	};
+	simulate(known, &load);
+	code.extend(load);
+	true
+}
+
+
+/// Updates what is known about reg0 to reg3 after running `code`, which only uses literals, moves, `add`, `sub` and
+/// `nor`.
+fn simulate(known: &mut KnownRegisters, code: &[u8]) {
+	for &instruction in code {
+		let operand = instruction & 0b111111;
+		match instruction >> 6 {
+			LITERAL_PREFIX => known[0] = Some(operand),
+			MOVE_PREFIX => {
+				let (from, to) = ((operand >> 3) as usize, (operand & 0b111) as usize);
+				if to < known.len() {
+					known[to] = known.get(from).copied().flatten();
 				}
-				Rule::hex_input => {
-					let as_str = inner.as_str().trim();
-					let without_prefix = &as_str[2..];
-					u8::from_str_radix(without_prefix, 16).unwrap()
+			}
+			ARITHMETIC_PREFIX => {
+				known[3] = match (known[1], known[2], operand) {
+					(Some(a), Some(b), Arithmetic::ADD) => Some(a.wrapping_add(b)),
+					(Some(a), Some(b), Arithmetic::SUB) => Some(a.wrapping_sub(b)),
+					(Some(a), Some(b), Arithmetic::NOR) => Some(!(a | b)),
+					_ => None,
 				}
-				_ => unreachable!(),
-			})
+			}
+			_ => unreachable!(),
+		}
+	}
+}
+
+
+/// The code that loads the label at `position` into reg0, padded with `nop` at the start to `length` bytes.
+fn load_label(position: u8, scratch: ScratchPolicy, length: usize) -> Vec<u8> {
+	let load = label_load(position, scratch);
+	let mut padded = vec![CONDITIONAL_PREFIX << 6 | Conditional::NOP; length - load.len()];
+	padded.extend(load);
+	padded
+}
+
+
+/// The shortest known code that loads `value` into reg0 without using any extensions.
+fn label_load(value: u8, scratch: ScratchPolicy) -> Vec<u8> {
+	if value <= 63 || scratch == ScratchPolicy::Forbid {
+		return vec![load_literal(value)];
+	}
+	let mut load = build_in_reg3(value);
+	load.push(move_between(3, 0));
+	if scratch == ScratchPolicy::KeepReg3 {
+		load.insert(0, move_between(3, 5));
+		load.push(move_between(5, 3));
+	}
+	load
+}
+
+
+/// The shortest known code that builds a value above 63 in reg3 from literals, overwriting reg0, reg1 and reg2.
+///
+/// Values are added together from literals, and `nor` of a value with itself flips every bit.
+fn build_in_reg3(value: u8) -> Vec<u8> {
+	let literal = load_literal;
+	let mov = move_between;
+	let add = ARITHMETIC_PREFIX << 6 | Arithmetic::ADD;
+	let nor = ARITHMETIC_PREFIX << 6 | Arithmetic::NOR;
+	match value {
+		0..=63 => unreachable!(),
+		192..=255 => vec![literal(!value), mov(0, 1), mov(0, 2), nor],
+		64..=126 => vec![literal(63), mov(0, 1), literal(value - 63), mov(0, 2), add],
+		127..=189 => vec![literal(63), mov(0, 1), mov(0, 2), add, mov(3, 1), literal(value - 126), mov(0, 2), add],
+		_ => vec![literal(63), mov(0, 1), literal(!value - 63), mov(0, 2), add, mov(3, 1), mov(3, 2), nor],
+	}
+}
+
+
+fn load_literal(value: u8) -> u8 {
+	LITERAL_PREFIX << 6 | value
+}
+
+
+fn move_between(from: u8, to: u8) -> u8 {
+	MOVE_PREFIX << 6 | from << 3 | to
+}
+
+
+fn parse_input<'a>(mut tree: Pairs<'a, Rule>, options: &ParseOptions, errors: &mut Vec<SpannedError>) -> (Vec<u8>, Pairs<'a, Rule>) {
+	let mut input = Vec::new();
+	if tree.peek().unwrap().as_rule() == Rule::inputs {
+		let lines = tree.next().unwrap().into_inner().filter(|l| l.as_rule() == Rule::input_line);
+		for value in lines.flat_map(|l| l.into_inner()) {
+			match parse_input_value(value.into_inner().next().unwrap(), options) {
+				Ok(bytes) => input.extend(bytes),
+				Err(e) => errors.push(e),
+			}
 		}
 	};
 	(input, tree)
 }
 
 
+/// The bytes of one value in the input section.
+fn parse_input_value(value: Pair<Rule>, options: &ParseOptions) -> Result<Vec<u8>, SpannedError> {
+	match value.as_rule() {
+		Rule::string => Ok(parse_string(value)),
+		Rule::unicode_char => Ok(unescape(unquote(value.as_str()))),
+		Rule::negative_input => match value.as_str()[1..].parse::<u8>() {
+			Ok(magnitude) if magnitude <= 128 => Ok(vec![magnitude.wrapping_neg()]),
+			_ => {
+				let error = SpannedError::new(format!("{} does not fit in a byte.", value.as_str()), trimmed_span(&value));
+				Err(error.with_note("Negative numbers are written as two's complement, which goes down to -128.".to_string()))
+			}
+		},
+		Rule::unsigned_input => match value.as_str().parse::<u8>() {
+			Ok(byte) => Ok(vec![byte]),
+			Err(_) => {
+				let error = SpannedError::new(format!("{} does not fit in a byte.", value.as_str()), trimmed_span(&value));
+				Err(error.with_note("A byte goes up to 255.".to_string()))
+			}
+		},
+		Rule::incbin => {
+			let path = String::from_utf8_lossy(&parse_string(value.clone().into_inner().next().unwrap())).into_owned();
+			let resolver = options.resolver;
+			resolver
+				.locate(&options.file_name, &path)
+				.and_then(|name| resolver.read_bytes(&name))
+				.map_err(|e| SpannedError::new(format!("Could not include \"{path}\": {e}"), trimmed_span(&value)))
+		}
+		_ => Ok(vec![parse_byte(value)]),
+	}
+}
+
+
+/// The bytes of a string literal, as UTF-8 with the escapes replaced.
+fn parse_string(string: Pair<Rule>) -> Vec<u8> {
+	unescape(unquote(string.as_str()))
+}
+
+
+/// Removes the quotes around a string or character literal.
+fn unquote(quoted: &str) -> &str {
+	&quoted[1..quoted.len() - 1]
+}
+
+
+/// Turns the text of a string or character literal into UTF-8, replacing the escapes.
+fn unescape(text: &str) -> Vec<u8> {
+	let mut bytes = Vec::new();
+	let mut characters = text.chars();
+	while let Some(character) = characters.next() {
+		if character != '\\' {
+			bytes.extend_from_slice(character.encode_utf8(&mut [0; 4]).as_bytes());
+			continue;
+		}
+		// '\' without anything after it is a backslash on its own.
+		let Some(escaped) = characters.next() else {
+			bytes.push(b'\\');
+			break;
+		};
+		bytes.push(match escaped {
+			'x' => u8::from_str_radix(&characters.by_ref().take(2).collect::<String>(), 16).unwrap(),
+			'n' => b'\n',
+			't' => b'\t',
+			'r' => b'\r',
+			'0' => b'\0',
+			// The other escapes are \\, \" and \'.
+			_ => escaped as u8,
+		});
+	}
+	bytes
+}
+
+
+/// Parses a byte written as a character, or a decimal, binary or hex number.
+fn parse_byte(inner: Pair<Rule>) -> u8 {
+	match inner.as_rule() {
+		// Character inputs are ASCII or an escape, which are always one byte.
+		Rule::char_input => unescape(unquote(inner.as_str().trim()))[0],
+		Rule::dec_input => {
+			let as_str = inner.as_str().trim();
+			as_str.parse::<u8>().unwrap()
+		}
+		Rule::bin_input => {
+			let as_str = inner.as_str().trim();
+			let without_prefix = &as_str[2..];
+			u8::from_str_radix(without_prefix, 2).unwrap()
+		}
+		Rule::hex_input => {
+			let as_str = inner.as_str().trim();
+			let without_prefix = &as_str[2..];
+			u8::from_str_radix(without_prefix, 16).unwrap()
+		}
+		_ => unreachable!(),
+	}
+}
+
+
 fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 	match instruction.as_rule() {
 		Rule::literal => {
@@ -198,12 +751,16 @@ fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 		}
 		Rule::nop => CONDITIONAL_PREFIX << 6 | Conditional::NOP,
 		Rule::j => CONDITIONAL_PREFIX << 6 | Conditional::JMP,
-		Rule::jez => CONDITIONAL_PREFIX << 6 | Conditional::JEZ,
-		Rule::jnz => CONDITIONAL_PREFIX << 6 | Conditional::JNZ,
-		Rule::jgez => CONDITIONAL_PREFIX << 6 | Conditional::JGEZ,
-		Rule::jgz => CONDITIONAL_PREFIX << 6 | Conditional::JGZ,
-		Rule::jlez => CONDITIONAL_PREFIX << 6 | Conditional::JLEZ,
-		Rule::jlz => CONDITIONAL_PREFIX << 6 | Conditional::JLZ,
+		Rule::halt => match instruction.into_inner().next() {
+			Some(register) => Halt::with_status_from(register.as_str().trim()[3..].parse().unwrap()),
+			None => Halt::SUCCESS,
+		},
+		Rule::jez => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JEZ,
+		Rule::jnz => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JNZ,
+		Rule::jgez => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JGEZ,
+		Rule::jgz => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JGZ,
+		Rule::jlez => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JLEZ,
+		Rule::jlz => CONDITIONAL_PREFIX << 6 | parse_condition_source(instruction) << 3 | Conditional::JLZ,
 
 		Rule::add => ARITHMETIC_PREFIX << 6 | Arithmetic::ADD,
 		Rule::sub => ARITHMETIC_PREFIX << 6 | Arithmetic::SUB,
@@ -214,6 +771,22 @@ fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 		Rule::and => ARITHMETIC_PREFIX << 6 | Arithmetic::AND,
 		Rule::nand => ARITHMETIC_PREFIX << 6 | Arithmetic::NAND,
 
+		Rule::shl => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::SHL,
+		Rule::shr => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::SHR,
+		Rule::asr => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::ASR,
+		Rule::rol => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::ROL,
+		Rule::ror => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::ROR,
+		Rule::mul => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::MUL,
+		Rule::mulh => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::MULH,
+		Rule::inc => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::INC,
+		Rule::dec => ARITHMETIC_PREFIX << 6 | ExtendedArithmetic::DEC,
+
+		Rule::imask => ARITHMETIC_PREFIX << 6 | Interrupt::IMASK,
+		Rule::ivec => ARITHMETIC_PREFIX << 6 | Interrupt::IVEC,
+		Rule::itimer => ARITHMETIC_PREFIX << 6 | Interrupt::ITIMER,
+		Rule::reti => ARITHMETIC_PREFIX << 6 | Interrupt::RETI,
+		Rule::icause => ARITHMETIC_PREFIX << 6 | Interrupt::ICAUSE,
+
 		Rule::mov => {
 			let mut inner = instruction.into_inner();
 			let from = inner.next().unwrap().as_str().trim();
@@ -224,12 +797,7 @@ fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 				let from = from.strip_prefix("reg").unwrap_or(from);
 				from.parse().unwrap()
 			};
-			let to_bin = if to.eq_ignore_ascii_case("output") || to.eq_ignore_ascii_case("o") || to.eq_ignore_ascii_case("out") {
-				ToStore::OUT
-			} else {
-				let to = to.strip_prefix("reg").unwrap_or(to);
-				to.parse().unwrap()
-			};
+			let to_bin = parse_target(to);
 
 			(MOVE_PREFIX << 6) | (from_bin << 3) | to_bin
 		}
@@ -239,6 +807,46 @@ fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 }
 
 
+/// Parses where a `mov` or `ldi` writes to.
+fn parse_target(to: &str) -> u8 {
+	if to.eq_ignore_ascii_case("output") || to.eq_ignore_ascii_case("o") || to.eq_ignore_ascii_case("out") {
+		ToStore::OUT
+	} else {
+		let to = to.strip_prefix("reg").unwrap_or(to);
+		to.parse().unwrap()
+	}
+}
+
+
+fn parse_condition_source(jump: Pair<Rule>) -> u8 {
+	let source = match jump.into_inner().next() {
+		Some(v) => v.into_inner().next().unwrap(),
+		None => return ConditionalSource::REG3,
+	};
+	match source.as_rule() {
+		Rule::compare_source => {
+			if source.into_inner().next().is_some() {
+				ConditionalSource::COMPARE_UNSIGNED
+			} else {
+				ConditionalSource::COMPARE
+			}
+		}
+		Rule::register_source => {
+			let register = source.as_str().trim();
+			match &register[3..] {
+				"0" => ConditionalSource::REG0,
+				"1" => ConditionalSource::REG1,
+				"2" => ConditionalSource::REG2,
+				"3" => ConditionalSource::REG3,
+				"4" => ConditionalSource::REG4,
+				"5" => ConditionalSource::REG5,
+				_ => unreachable!(),
+			}
+		}
+		_ => unreachable!(),
+	}
+}
+
 //add = {WHITE_SPACE* ~ ^"add" ~ end_of_line}
 // sub = {WHITE_SPACE* ~ ^"sub" ~ end_of_line}
 // or = {WHITE_SPACE* ~ ^"or" ~ end_of_line}
@@ -249,23 +857,47 @@ fn parse_instruction(instruction: Pair<Rule>) -> u8 {
 // nand = {WHITE_SPACE* ~ ^"nand" ~ end_of_line}
 #[cfg(test)]
 mod tests {
-    use color_eyre::Result;
-    use pest::iterators::Pair;
+	use bytecode_interpreter::run::{
+		Arithmetic, ARITHMETIC_PREFIX, Conditional, CONDITIONAL_PREFIX, ConditionalSource, ExtendedArithmetic, Extension,
+		Halt, Input, Machine, Output, Queue,
+	};
+	use color_eyre::Result;
+	use pest::iterators::Pair;
 
-    use crate::assembler::lexer::lex;
-    use crate::assembler::parser::parse;
-    use crate::lexer::lex;
-    use crate::parser::parse;
+	use crate::diagnostics::{diagnose, diagnose_with, Severity};
+	use crate::lexer::lex;
+	use crate::parser::{parse, parse_with, ParseOptions, ScratchPolicy};
 
-    use super::Rule;
+	use super::Rule;
 
 
-    fn print_ast(program: &str) -> Result<()> {
-		let file: Pair<Rule> = lex(program)?;
+	fn print_ast(program: &str) -> Result<()> {
+		let _file: Pair<Rule> = lex(program)?;
 		Ok(())
 	}
 
 
+	/// Assembles `source`, which is put after a `program:` line.
+	fn assemble(source: &str) -> Vec<u8> {
+		parse(&format!("program:\n{source}\n")).unwrap().program().to_vec()
+	}
+
+
+	/// Assembles `source` like [`assemble`] with `scratch` and runs it, returning its exit status.
+	fn run_with(source: &str, scratch: ScratchPolicy) -> u8 {
+		let options = ParseOptions { scratch, ..Default::default() };
+		let parsed = parse_with(&format!("program:\n{source}\n"), &options).unwrap();
+		Machine::new(parsed.program(), Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::NONE).run().unwrap()
+	}
+
+
+	/// Jumps with `jnz` to a label above 63 while reg3 is 0, exiting with 1 if the jump is taken.
+	fn jump_to_far_label(scratch: ScratchPolicy) -> u8 {
+		let padding = "nop\n".repeat(64);
+		run_with(&format!("0\nmov 0 3\nfar\njnz\nhalt reg3\n{padding}label far:\n1\nmov 0 3\nhalt reg3"), scratch)
+	}
+
+
 	#[test]
 	fn dummy_test() -> Result<()> {
 		let s = r#"
@@ -298,8 +930,265 @@ mod tests {
             j
             "#;
 		print_ast(s)?;
-		let r = parse(s)?;
-		// Panic to see print output.
-		panic!()
+		parse(s)?;
+		Ok(())
+	}
+
+
+	/// Mnemonics must not run into the value of a constant on the next line.
+	#[test]
+	fn constants_after_mnemonics() {
+		let mnemonics = [
+			"nop", "j", "jez", "jnz", "jgez", "jgz", "jlez", "jlz", "jez reg4", "jgz reg1 reg2", "jgz reg1 reg2 unsigned", "halt",
+			"halt reg3", "shl", "shr", "asr", "rol", "ror", "mul", "mulh", "inc", "dec", "imask", "ivec", "itimer", "reti", "icause",
+			"ldi 200 -> 4", "print \"hi\"",
+		];
+		for mnemonic in mnemonics {
+			assert_eq!(assemble(&format!("x = 7\n{mnemonic}\nx")), assemble(&format!("{mnemonic}\n7")), "{mnemonic}");
+		}
+	}
+
+
+	#[test]
+	fn arithmetic_encodings() {
+		let cases = [
+			("add", Arithmetic::ADD),
+			("sub", Arithmetic::SUB),
+			("and", Arithmetic::AND),
+			("nand", Arithmetic::NAND),
+			("or", Arithmetic::OR),
+			("nor", Arithmetic::NOR),
+			("xor", Arithmetic::XOR),
+			("xnor", Arithmetic::XNOR),
+			("shl", ExtendedArithmetic::SHL),
+			("shr", ExtendedArithmetic::SHR),
+			("asr", ExtendedArithmetic::ASR),
+			("rol", ExtendedArithmetic::ROL),
+			("ror", ExtendedArithmetic::ROR),
+			("mul", ExtendedArithmetic::MUL),
+			("mulh", ExtendedArithmetic::MULH),
+			("inc", ExtendedArithmetic::INC),
+			("dec", ExtendedArithmetic::DEC),
+		];
+		for (mnemonic, operation) in cases {
+			assert_eq!(assemble(mnemonic), [ARITHMETIC_PREFIX << 6 | operation], "{mnemonic}");
+			assert_eq!(assemble(&mnemonic.to_uppercase()), [ARITHMETIC_PREFIX << 6 | operation], "{mnemonic}");
+		}
+	}
+
+
+	#[test]
+	fn halt_encodings() {
+		assert_eq!(assemble("halt"), [Halt::SUCCESS]);
+		for register in 0..=5 {
+			assert_eq!(assemble(&format!("halt reg{register}")), [Halt::with_status_from(register)]);
+		}
+		assert!(parse("program:\nhalt reg6\n").is_err());
+	}
+
+
+	#[test]
+	fn conditional_encodings() {
+		let cases = [
+			("jez", ConditionalSource::REG3, Conditional::JEZ),
+			("jez reg3", ConditionalSource::REG3, Conditional::JEZ),
+			("jnz reg0", ConditionalSource::REG0, Conditional::JNZ),
+			("jgz reg4", ConditionalSource::REG4, Conditional::JGZ),
+			("jlz reg5", ConditionalSource::REG5, Conditional::JLZ),
+			("jgez reg1", ConditionalSource::REG1, Conditional::JGEZ),
+			("jlez reg2", ConditionalSource::REG2, Conditional::JLEZ),
+			("jgz reg1 reg2", ConditionalSource::COMPARE, Conditional::JGZ),
+			("jlz reg1 reg2 unsigned", ConditionalSource::COMPARE_UNSIGNED, Conditional::JLZ),
+		];
+		for (source, condition_source, condition) in cases {
+			assert_eq!(assemble(source), [CONDITIONAL_PREFIX << 6 | condition_source << 3 | condition], "{source}");
+		}
+	}
+
+
+	#[test]
+	fn keep_reg3_loads_far_labels_without_changing_conditions() {
+		assert_eq!(jump_to_far_label(ScratchPolicy::KeepReg3), 0);
+	}
+
+
+	#[test]
+	fn far_labels_are_forbidden_by_default() {
+		let padding = "nop\n".repeat(64);
+		let source = format!("program:\nfar\nj\n{padding}label far:\nhalt\n");
+		let diagnostics = diagnose(&source);
+		assert_eq!(diagnostics.len(), 1);
+		assert_eq!(diagnostics[0].severity, Severity::Error);
+		assert_eq!(diagnostics[0].span, 9..12);
+		assert_eq!(diagnostics[0].notes, ["The label `far` is at 66, but literals can only hold values up to 63."]);
+	}
+
+
+	#[test]
+	fn far_label_loads_are_warnings() {
+		let padding = "nop\n".repeat(64);
+		let source = format!("program:\nmacro go():\n    far\n    j\nend_macro:\ngo()\nnear\nj\nlabel near:\n{padding}label far:\nhalt\n");
+		for (scratch, message) in [
+			(ScratchPolicy::KeepReg3, "Loading the label `far` overwrites reg1, reg2 and reg5."),
+			(ScratchPolicy::Clobber, "Loading the label `far` overwrites reg1, reg2 and reg3."),
+		] {
+			let options = ParseOptions { scratch, ..Default::default() };
+			let warnings = parse_with(&source, &options).unwrap().warnings().to_vec();
+			assert_eq!(warnings, diagnose_with(&source, &options));
+			// Only the far label is a warning, and the warning points at the macro call it comes from.
+			assert_eq!(warnings.len(), 1);
+			assert_eq!(warnings[0].severity, Severity::Warning);
+			assert_eq!(warnings[0].message, message);
+			assert_eq!(warnings[0].span, source.rfind("go()").unwrap()..source.find("\nnear").unwrap());
+			assert!(warnings[0].notes.iter().any(|n| n.contains("The label is at")), "{:?}", warnings[0].notes);
+		}
+	}
+
+
+	#[test]
+	fn clobber_loads_far_labels_into_reg3() {
+		assert_eq!(jump_to_far_label(ScratchPolicy::Clobber), 1);
+	}
+
+
+	#[test]
+	fn far_labels_are_reached_with_every_scratch_policy() {
+		let padding = "nop\n".repeat(64);
+		let source = format!("far\nj\n{padding}label far:\n5\nmov 0 3\nhalt reg3");
+		assert_eq!(run_with(&source, ScratchPolicy::Clobber), 5);
+		assert_eq!(run_with(&source, ScratchPolicy::KeepReg3), 5);
+	}
+
+
+	/// Assembles `source` like [`assemble`] and runs it without input, returning its registers and output.
+	fn run_program(source: &str) -> ([u8; 6], Vec<u8>) {
+		let output = Queue::default();
+		let mut machine = Machine::new_owned(assemble(source), Input::ARRAY(&[]), Output::QUEUE(output.clone()), Extension::NONE);
+		machine.run().unwrap();
+		(machine.registers(), output.take().into())
+	}
+
+
+	#[test]
+	fn ldi_loads_every_byte_into_every_target() {
+		for value in 0..=255 {
+			for target in 0..6 {
+				let (registers, _) = run_program(&format!("ldi {value} -> reg{target}"));
+				assert_eq!(registers[target], value, "ldi {value} -> reg{target}");
+			}
+			assert_eq!(run_program(&format!("ldi {value} -> out")).1, [value], "ldi {value} -> out");
+		}
+	}
+
+
+	#[test]
+	fn ldi_values_in_every_notation() {
+		let cases = [("'A'", b'A'), ("'\\n'", b'\n'), ("0b11001000", 200), ("0xc8", 200), ("0xFF", 255), ("007", 7), ("0x0", 0), ("0x00", 0), ("0x000f", 15), ("0b0", 0), ("0b000", 0)];
+		for (source, value) in cases {
+			assert_eq!(run_program(&format!("ldi {source} -> 4")).0[4], value, "{source}");
+		}
+	}
+
+
+	#[test]
+	fn ldi_prefixes_need_digits() {
+		for source in ["0x", "0b", "0X", "0xg", "0b2"] {
+			let diagnostics = diagnose(&format!("program:\nldi {source} -> 4\n"));
+			assert_eq!(diagnostics.len(), 1, "{source}");
+			assert_eq!(diagnostics[0].severity, Severity::Error, "{source}");
+		}
+	}
+
+
+	#[test]
+	fn print_escapes() {
+		let cases: [(&str, &[u8]); 5] = [
+			("\\n\\t\\r\\0", b"\n\t\r\0"),
+			("\\\\ \\\" \\'", b"\\ \" '"),
+			("\\x41\\x7e\\xff\\x00", b"\x41\x7e\xff\x00"),
+			("Hi 😀", "Hi 😀".as_bytes()),
+			("", b""),
+		];
+		for (string, bytes) in cases {
+			assert_eq!(run_program(&format!("print \"{string}\"")).1, bytes, "{string}");
+		}
+	}
+
+
+	#[test]
+	fn print_writes_every_byte() {
+		let string: String = (0..=255).map(|byte| format!("\\x{byte:02x}")).collect();
+		let bytes: Vec<u8> = (0..=255).collect();
+		// A program holds at most 255 bytes, so the string is printed in pieces.
+		for (piece, expected) in string.as_bytes().chunks(4 * 16).zip(bytes.chunks(16)) {
+			let piece = std::str::from_utf8(piece).unwrap();
+			assert_eq!(run_program(&format!("print \"{piece}\"")).1, expected, "{piece}");
+		}
+		let reversed: String = (0..=255).rev().map(|byte| format!("\\x{byte:02x}")).collect();
+		for (piece, expected) in reversed.as_bytes().chunks(4 * 16).zip(bytes.rchunks(16)) {
+			let piece = std::str::from_utf8(piece).unwrap();
+			let expected: Vec<u8> = expected.iter().rev().copied().collect();
+			assert_eq!(run_program(&format!("print \"{piece}\"")).1, expected, "{piece}");
+		}
+	}
+
+
+	/// The input of a program with `input` as its input section.
+	fn input_of(input: &str) -> Result<Vec<u8>> {
+		Ok(parse(&format!("input:\n{input}\nprogram:\nnop\n"))?.input().to_vec())
+	}
+
+
+	#[test]
+	fn input_values() {
+		let cases: [(&str, &[u8]); 8] = [
+			("'H', 0x10, -1, \"hi\\n\"", b"H\x10\xffhi\n"),
+			("0, 9, 99, 255, 007", &[0, 9, 99, 255, 7]),
+			("-128 , -1", &[128, 255]),
+			("0b101,0xff  ", &[5, 255]),
+			("0x0, 0x00, 0b0, 0b0000, 0x001", &[0, 0, 0, 0, 1]),
+			("1 // A comment.", &[1]),
+			("2 /* A comment. */", &[2]),
+			("1\n2, 3", &[1, 2, 3]),
+		];
+		for (input, bytes) in cases {
+			assert_eq!(input_of(input).unwrap(), bytes, "{input}");
+		}
+	}
+
+
+	#[test]
+	fn input_values_need_a_boundary() {
+		for input in ["1 2", "25 6", "'a' 'b'", "0x123", "0b111111111", "\"a\" 1", "0x", "0b", "0X, 1", "1, 0b", "0xg", "0b2"] {
+			assert!(input_of(input).is_err(), "{input}");
+		}
+	}
+
+
+	#[test]
+	fn input_numbers_that_do_not_fit_in_a_byte() {
+		for input in ["256", "999", "1000", "-129", "-999", "1, 300"] {
+			let diagnostics = diagnose(&format!("input:\n{input}\nprogram:\nnop\n"));
+			let number = input.rsplit(", ").next().unwrap();
+			assert_eq!(diagnostics.len(), 1, "{input}");
+			assert_eq!(diagnostics[0].message, format!("{number} does not fit in a byte."));
+		}
+	}
+
+
+	#[test]
+	fn extensions_used_by_the_program() {
+		let cases = [
+			("nop\nadd\njez\nmov 4 out", Extension::NONE),
+			("shl", Extension::EXTENDED_ALU),
+			("inc", Extension::EXTENDED_ALU),
+			("jez reg4", Extension::REGISTER_CONDITIONALS),
+			("jgz reg1 reg2 unsigned", Extension::REGISTER_CONDITIONALS),
+			("imask", Extension::INTERRUPTS),
+			("reti\nmul\njnz reg0", Extension::INTERRUPTS | Extension::EXTENDED_ALU | Extension::REGISTER_CONDITIONALS),
+		];
+		for (source, extensions) in cases {
+			assert_eq!(parse(&format!("program:\n{source}\n")).unwrap().extensions(), extensions, "{source}");
+		}
 	}
 }
@@ -1,5 +1,13 @@
-mod macro_expander;
+pub(crate) mod macro_expander;
+pub(crate) mod source_map;
 use color_eyre::Result;
-pub fn preprocess(input: &str) -> Result<String> {
-	macro_expander::expand_macros(input)
-}
\ No newline at end of file
+
+use crate::include::{expand_includes, Resolver};
+use source_map::SourceMap;
+/// Expands includes and then macros. `file_name` is the name of the file `input` is in, as `resolver` names files.
+pub fn preprocess(input: &str, file_name: &str, resolver: &dyn Resolver) -> Result<(String, SourceMap)> {
+	let (included, mut source_map) = expand_includes(input, file_name, resolver)?;
+	let (expanded, macros) = macro_expander::expand_macros(&included).map_err(|e| source_map.remap(e))?;
+	source_map.extend(macros);
+	Ok((expanded, source_map))
+}
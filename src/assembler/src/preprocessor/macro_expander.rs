@@ -1,17 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
-use aho_corasick::{AhoCorasickBuilder, MatchKind};
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{eyre, Report};
 use color_eyre::Result;
 use pest::iterators::{Pair, Pairs};
 
+use crate::diagnostics::{closest, trimmed_span, ErrorList, SpannedError};
 use crate::lexer::lex;
 use crate::lexer::Rule;
+use crate::preprocessor::source_map::{RoundMap, SourceMap};
+use crate::symbols::words;
 
 const MAX_DEPTH: u32 = 100;
 
 
-fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()>> {
+/// Expands one round of macro calls and constants. `expansions` counts the macro calls expanded so far, in every round.
+fn expand_macros_recurse(input: &str, expanded: &mut String, round: &mut RoundMap, expansions: &mut u32) -> Option<Result<()>> {
     expanded.clear();
     let file = lex(&input);
     if let Err(e) = file {
@@ -22,6 +26,7 @@ fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()
     let inputs = tree.next().unwrap();
     assert_eq!(inputs.as_rule(), Rule::inputs);
     expanded.push_str(inputs.as_str());
+    round.copied(inputs.as_span().start()..inputs.as_span().end());
     let program = tree.next().unwrap();
     assert_eq!(program.as_rule(), Rule::program);
 
@@ -29,21 +34,35 @@ fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()
     let start_of_program = actions.next().unwrap();
     assert_eq!(start_of_program.as_rule(), Rule::start_of_program);
     expanded.push_str(start_of_program.as_str());
+    round.copied(start_of_program.as_span().start()..start_of_program.as_span().end());
     let (macros, constants) = parse_macros_and_constants(actions.clone());
+    let definitions = actions.clone().filter(|a| a.as_rule() == Rule::action).map(|a| a.into_inner().next().unwrap());
+    // Blanking out a macro definition to recover from one of these would make every call to it an error too, so they are
+    // reported for every macro at once.
+    let parameter_errors: Vec<SpannedError> =
+        definitions.filter(|d| d.as_rule() == Rule::full_macro).filter_map(|d| parameter_error(&d)).collect();
+    if !parameter_errors.is_empty() {
+        return Some(Err(ErrorList::found_together(parameter_errors)));
+    }
     let mut number_of_macro_calls_or_constants = 0u32;
     for action in actions {
         match action.as_rule() {
             Rule::action => {
                 let node = action.into_inner().next().unwrap();
+                let span = node.as_span().start()..node.as_span().end();
+                let call = trimmed_span(&node);
                 match node.as_rule() {
                     Rule::macro_call => {
                         let mut contents = node.into_inner();
                         let ident = contents.next().unwrap().as_str().trim();
                         let macro_def = macros.get(ident);
                         if macro_def == None {
-                            return Some(Err(eyre!(
-                                "Tried to call macro \"{ident}\" that does not exist."
-                            )));
+                            let error = SpannedError::new(format!("Tried to call macro \"{ident}\" that does not exist."), call);
+                            let error = match closest(ident, macros.keys().copied()) {
+                                Some(name) => error.with_help(format!("Did you mean `{name}`?")),
+                                None => error,
+                            };
+                            return Some(Err(Report::new(error)));
                         }
                         let mut macro_def = macro_def.unwrap().clone().into_inner();
                         let macro_args = macro_def.next().unwrap().into_inner().nth(1).unwrap().into_inner();
@@ -51,19 +70,24 @@ fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()
                         let expected_number_of_arguments = macro_args.clone().filter(|x| !x.as_str().trim().is_empty()).count();
                         let provided_number_of_arguments = args.clone().filter(|x| !x.as_str().trim().is_empty()).count();
                         if expected_number_of_arguments != provided_number_of_arguments {
-                            return Some(Err(eyre!("Macro {ident} expects {expected_number_of_arguments} arguments but received {provided_number_of_arguments}")));
+                            let parameters: Vec<&str> = macro_args.clone().map(|a| a.as_str().trim()).collect();
+                            let error = SpannedError::new(format!("Macro {ident} expects {expected_number_of_arguments} arguments but received {provided_number_of_arguments}"), call)
+                                .with_note(format!("{ident} is defined as `macro {ident}({}):`", parameters.join(", ")));
+                            return Some(Err(Report::new(error)));
                         }
 
-                        let macro_body = macro_def.next().unwrap().as_str();
+                        *expansions += 1;
+                        let macro_body = rename_local_labels(macro_def.next().unwrap().as_str(), *expansions);
 
                         if expected_number_of_arguments != 0 {
                             let pats: Vec<&str> = macro_args.map(|marg| marg.as_str()).collect();
                             let reps: Vec<&str> = args.map(|arg| arg.as_str()).collect();
-                            let ac = AhoCorasickBuilder::new().match_kind(MatchKind::LeftmostFirst).auto_configure(&pats).build(&pats);
-                            let macro_body = ac.replace_all(macro_body, &reps);
+                            let macro_body = substitute_arguments(&macro_body, &pats, &reps);
                             expanded.push_str(macro_body.as_str());
+                            round.generated(call, macro_body.len());
                         } else {
-                            expanded.push_str(macro_body);
+                            expanded.push_str(&macro_body);
+                            round.generated(call, macro_body.len());
                         };
 
                         number_of_macro_calls_or_constants += 1;
@@ -73,13 +97,22 @@ fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()
                         let body = constants.get(ident);
                         if body == None {
                             expanded.push_str(node.as_str());
+                            round.copied(span);
                             continue;
                         }
                         let body = body.unwrap().clone().into_inner().nth(1).unwrap().as_str();
+                        // The newline before the constant separates its value from the instruction before it.
+                        let leading = node.as_str().len() - node.as_str().trim_start().len();
+                        expanded.push_str(&node.as_str()[..leading]);
+                        round.copied(span.start..span.start + leading);
                         expanded.push_str(body);
+                        round.generated(call, body.len());
                         number_of_macro_calls_or_constants += 1
                     }
-                    _ => expanded.push_str(node.as_str()),
+                    _ => {
+                        expanded.push_str(node.as_str());
+                        round.copied(span);
+                    }
                 }
                 //expanded.push('\n');
             }
@@ -94,18 +127,25 @@ fn expand_macros_recurse(input: &str, expanded: &mut String) -> Option<Result<()
 }
 
 
-pub fn expand_macros(mut input: &str) -> Result<String> {
+/// Expands every macro call and constant. Returns the expanded source and how it maps back to `input`.
+pub fn expand_macros(mut input: &str) -> Result<(String, SourceMap)> {
     let mut expanded = String::with_capacity((input.len() * 3) / 2);
     let mut input_s = String::new();
+    let mut source_map = SourceMap::default();
+    let mut expansions = 0;
     for i in 0u32.. {
         if i == MAX_DEPTH {
             return Err(eyre!("Max recursion expansion limit reached! Having more than {MAX_DEPTH} nested macros is not allowed."));
         }
-        let round = expand_macros_recurse(input, &mut expanded);
+        let mut round_map = RoundMap::default();
+        let round = expand_macros_recurse(input, &mut expanded, &mut round_map, &mut expansions);
         if let None = round {
-            return Ok(expanded);
+            source_map.push_round(round_map);
+            return Ok((expanded, source_map));
         }
-        round.unwrap()?;
+        // Errors point at this round's input, which the rounds so far map back to the original source.
+        round.unwrap().map_err(|e| source_map.remap(e))?;
+        source_map.push_round(round_map);
         input_s.reserve((expanded.len() * 3) / 2);
         std::mem::swap(&mut input_s, &mut expanded);
         let i = input_s.as_str();
@@ -120,6 +160,77 @@ pub fn expand_macros(mut input: &str) -> Result<String> {
 }
 
 
+/// Gives the labels defined in a macro body names of their own for one expansion, so a macro with a loop in it can be
+/// called more than once. `loop` becomes `loop__3` in the third expansion.
+///
+/// Labels named by a parameter, like `label %done:`, belong to the caller and keep the name they are given.
+fn rename_local_labels(body: &str, expansion: u32) -> String {
+    let local: HashSet<&str> = words(body)
+        .windows(2)
+        .filter(|w| body[w[0].clone()].eq_ignore_ascii_case("label") && body[w[1].end..].trim_start().starts_with(':'))
+        .map(|w| &body[w[1].clone()])
+        .filter(|name| !name.starts_with('%'))
+        .collect();
+    replace_words(body, |word| local.contains(word).then(|| format!("{word}__{expansion}")))
+}
+
+
+/// Copies `text`, replacing every word that `replacement` returns something for.
+pub(crate) fn replace_words(text: &str, mut replacement: impl FnMut(&str) -> Option<String>) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut copied_until = 0;
+    for word in words(text) {
+        if let Some(new) = replacement(&text[word.clone()]) {
+            replaced.push_str(&text[copied_until..word.start]);
+            replaced.push_str(&new);
+            copied_until = word.end;
+        }
+    }
+    replaced.push_str(&text[copied_until..]);
+    replaced
+}
+
+
+/// Replaces every parameter of a macro in `body` with the matching argument.
+///
+/// Only whole words are replaced, so `%a` is left alone in `%ab`, and so are comments and character and string literals.
+pub(crate) fn substitute_arguments(body: &str, parameters: &[&str], arguments: &[&str]) -> String {
+    replace_words(body, |word| parameters.iter().position(|p| p.trim() == word).map(|i| arguments[i].to_string()))
+}
+
+
+/// An error if the body of the macro defined by `definition` uses a `%name` that is not one of its parameters, or if one
+/// of its parameters is never used.
+fn parameter_error(definition: &Pair<Rule>) -> Option<SpannedError> {
+    let mut parts = definition.clone().into_inner();
+    let mut header = parts.next().unwrap().into_inner();
+    let ident = header.next().unwrap().as_str().trim();
+    let parameters: Vec<Pair<Rule>> = header.next().unwrap().into_inner().collect();
+    let names: Vec<&str> = parameters.iter().map(|p| p.as_str().trim()).collect();
+    let body = parts.next().unwrap();
+    let offset = body.as_span().start();
+    let used: Vec<(&str, Range<usize>)> = words(body.as_str())
+        .into_iter()
+        .map(|w| (&body.as_str()[w.clone()], offset + w.start..offset + w.end))
+        .filter(|(word, _)| word.starts_with('%'))
+        .collect();
+
+    if let Some((word, span)) = used.iter().find(|(word, _)| !names.contains(word)) {
+        let error = SpannedError::new(format!("Macro {ident} has no parameter `{word}`."), span.clone())
+            .with_note(format!("{ident} is defined as `macro {ident}({}):`", names.join(", ")));
+        return Some(match closest(word, names.iter().copied()) {
+            Some(name) => error.with_help(format!("Did you mean `{name}`?")),
+            None => error,
+        });
+    }
+    let unused = parameters.iter().zip(&names).find(|(_, name)| !used.iter().any(|(word, _)| word == *name));
+    unused.map(|(parameter, name)| {
+        SpannedError::new(format!("The parameter `{name}` of macro {ident} is never used."), trimmed_span(parameter))
+            .with_help("Remove it, or use it in the body of the macro.".to_string())
+    })
+}
+
+
 type Macros<'a> = HashMap<&'a str, Pair<'a, Rule>>;
 type Constants<'a> = HashMap<&'a str, Pair<'a, Rule>>;
 
@@ -149,3 +260,104 @@ fn parse_macros_and_constants(actions: Pairs<Rule>) -> (Macros, Constants) {
     }
     (macros, constants)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use bytecode_interpreter::run::{Extension, Input, Machine, Output, Queue};
+
+    use crate::diagnostics::diagnose;
+    use crate::parser::parse;
+
+
+    /// Assembles `source`, which is put after a `program:` line, runs it and returns the output.
+    fn output_of(source: &str) -> Vec<u8> {
+        let parsed = parse(&format!("program:\n{source}\n")).unwrap();
+        let output = Queue::default();
+        let mut machine = Machine::new_owned(parsed.program().to_vec(), Input::ARRAY(&[]), Output::QUEUE(output.clone()), Extension::NONE);
+        machine.run().unwrap();
+        output.take().into()
+    }
+
+
+    const SKIP: &str = "macro emit(%char):\n    over\n    j\n    ldi '!' -> out\n    label over:\n    ldi %char -> out\nend_macro:\n";
+
+
+    #[test]
+    fn labels_in_macros_are_renamed_for_every_call() {
+        assert_eq!(output_of(&format!("{SKIP}emit('a')\nemit('b')\nemit('c')")), b"abc");
+    }
+
+
+    #[test]
+    fn labels_in_nested_macros_are_renamed_for_every_call() {
+        let source = format!("{SKIP}macro twice(%char):\n    emit(%char)\n    skip\n    j\n    label skip:\n    emit(%char)\nend_macro:\ntwice('a')\ntwice('b')");
+        assert_eq!(output_of(&source), b"aabb");
+    }
+
+
+    #[test]
+    fn labels_named_by_a_parameter_belong_to_the_caller() {
+        let source = "macro mark(%name):\n    label %name:\nend_macro:\nafter\nj\nldi 'x' -> out\nmark(after)\nldi 'y' -> out";
+        assert_eq!(output_of(source), b"y");
+    }
+
+
+    #[test]
+    fn duplicate_labels_are_an_error() {
+        let source = "program:\nlabel twice:\nnop\nlabel twice:\n";
+        let diagnostics = diagnose(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "The label `twice` is defined more than once.");
+        assert_eq!(diagnostics[0].span.start, source.rfind("label").unwrap());
+        assert_eq!(diagnostics[0].notes, ["It is first defined on line 2."]);
+
+        // A label named by a parameter is not renamed, so naming it twice is a duplicate too.
+        let diagnostics = diagnose("program:\nmacro mark(%name):\n    label %name:\nend_macro:\nmark(here)\nmark(here)\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "The label `here` is defined more than once.");
+    }
+
+
+    #[test]
+    fn parameters_are_substituted_as_whole_words() {
+        let source = "macro pair(%a, %ab):\n    ldi %ab -> out\n    ldi %a -> out\n    // %a is not replaced in comments.\nend_macro:\npair('x', 'y')";
+        assert_eq!(output_of(source), b"yx");
+    }
+
+
+    #[test]
+    fn parameters_after_quote_characters_are_substituted() {
+        let source = "macro pair(%a, %b):\n    ldi %a -> out\n    ldi %b -> out\nend_macro:\nmacro quoted(%value):\n    pair(''', %value)\nend_macro:\nquoted('x')";
+        assert_eq!(output_of(source), b"'x");
+    }
+
+
+    #[test]
+    fn unknown_parameters_are_an_error() {
+        let source = "program:\nmacro show(%char):\n    ldi %chr -> out\nend_macro:\nshow('a')\n";
+        let diagnostics = diagnose(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Macro show has no parameter `%chr`.");
+        assert_eq!(diagnostics[0].span, source.find("%chr").unwrap()..source.find(" ->").unwrap());
+        assert_eq!(diagnostics[0].help.as_deref(), Some("Did you mean `%char`?"));
+    }
+
+
+    #[test]
+    fn unused_parameters_are_an_error() {
+        let source = "program:\nmacro show(%char, %count):\n    ldi %char -> out\nend_macro:\nshow('a', 2)\n";
+        let diagnostics = diagnose(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "The parameter `%count` of macro show is never used.");
+        assert_eq!(diagnostics[0].span, source.find("%count").unwrap()..source.find("):").unwrap());
+    }
+
+
+    #[test]
+    fn parameter_errors_of_every_macro_are_reported_together() {
+        let source = "program:\nmacro a(%x):\n    ldi %y -> out\nend_macro:\nmacro b(%x):\n    nop\nend_macro:\na(1)\nb(2)\nnop\n";
+        let messages: Vec<String> = diagnose(source).into_iter().map(|d| d.message).collect();
+        assert_eq!(messages, ["Macro a has no parameter `%y`.", "The parameter `%x` of macro b is never used."]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use color_eyre::eyre::Report;
+
+use crate::diagnostics::{ErrorList, LineColumn, SpannedError};
+
+
+/// Maps byte positions in the expanded source back to the source the user wrote.
+///
+/// Expanding includes and every round of macro expansion add a [`RoundMap`]. Text that was copied maps back to where it was copied from, and text that came out of an included file, a macro or a constant maps back to the directive or call.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SourceMap {
+	rounds: Vec<RoundMap>,
+	/// Where the text that came out of included files is in those files.
+	included: Vec<IncludedText>,
+}
+
+
+/// Text that was copied from an included file.
+#[derive(Debug, Clone)]
+pub(crate) struct IncludedText {
+	/// Where the text is in the source after includes were expanded.
+	pub(crate) expanded: Range<usize>,
+	/// The name of the file, as the resolver named it.
+	pub(crate) file: Rc<str>,
+	/// The whole file, and where in it the text starts.
+	pub(crate) source: Rc<str>,
+	pub(crate) start: usize,
+}
+
+
+/// How the output of one round of macro expansion relates to its input.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct RoundMap {
+	segments: Vec<Segment>,
+	length: usize,
+}
+
+
+#[derive(Debug, Clone)]
+struct Segment {
+	expanded_start: usize,
+	original: Range<usize>,
+	/// Whether the text was copied as is, so positions inside it map one to one.
+	copied: bool,
+	/// Whether the text came out of an included file rather than a macro or constant.
+	included: bool,
+}
+
+
+impl RoundMap {
+	/// Records that the text at `original` in the input was copied to the end of the output.
+	pub(crate) fn copied(&mut self, original: Range<usize>) {
+		let length = original.len();
+		self.push(Segment { expanded_start: self.length, original, copied: true, included: false }, length);
+	}
+
+	/// Records that `length` bytes were added to the end of the output by the macro call or constant at `call`.
+	pub(crate) fn generated(&mut self, call: Range<usize>, length: usize) {
+		self.push(Segment { expanded_start: self.length, original: call, copied: false, included: false }, length);
+	}
+
+	/// Records that `length` bytes were added to the end of the output by the include directive at `directive`.
+	pub(crate) fn included(&mut self, directive: Range<usize>, length: usize) {
+		self.push(Segment { expanded_start: self.length, original: directive, copied: false, included: true }, length);
+	}
+
+	fn push(&mut self, segment: Segment, length: usize) {
+		if length > 0 {
+			self.segments.push(segment);
+			self.length += length;
+		}
+	}
+
+	fn map(&self, span: Range<usize>) -> Range<usize> {
+		let start = match self.segment_at(span.start) {
+			Some(s) if s.copied => s.original.start + (span.start - s.expanded_start),
+			Some(s) => s.original.start,
+			None => self.end_of_input(),
+		};
+		let end = if span.end <= span.start {
+			start
+		} else {
+			match self.segment_at(span.end - 1) {
+				Some(s) if s.copied => s.original.start + (span.end - s.expanded_start),
+				Some(s) => s.original.end,
+				None => self.end_of_input(),
+			}
+		};
+		start..end.max(start)
+	}
+
+	/// Whether the text at `position` came out of a macro or constant.
+	fn is_generated(&self, position: usize) -> bool {
+		self.segment_at(position).is_some_and(|s| !s.copied && !s.included)
+	}
+
+	fn is_included(&self, position: usize) -> bool {
+		self.segment_at(position).is_some_and(|s| s.included)
+	}
+
+	fn segment_at(&self, position: usize) -> Option<&Segment> {
+		let index = self.segments.partition_point(|s| s.expanded_start <= position).checked_sub(1)?;
+		let segment = &self.segments[index];
+		let length = match self.segments.get(index + 1) {
+			Some(next) => next.expanded_start - segment.expanded_start,
+			None => self.length - segment.expanded_start,
+		};
+		(position < segment.expanded_start + length).then_some(segment)
+	}
+
+	fn end_of_input(&self) -> usize {
+		self.segments.iter().map(|s| s.original.end).max().unwrap_or(0)
+	}
+}
+
+
+/// A note for an error at the byte `offset` in the included file `file`, which is reported at the include directive.
+pub(crate) fn included_note(file: &str, source: &str, offset: usize) -> String {
+	let position = LineColumn::of(source, offset);
+	format!("The error is in `{file}` at line {}, column {}, which is included here.", position.line, position.column)
+}
+
+
+impl SourceMap {
+	pub(crate) fn push_round(&mut self, round: RoundMap) {
+		self.rounds.push(round);
+	}
+
+	/// A map for the source after includes were expanded, where `included` came out of included files.
+	pub(crate) fn from_includes(round: RoundMap, included: Vec<IncludedText>) -> Self {
+		Self { rounds: vec![round], included }
+	}
+
+	/// Adds the rounds of `later`, which expanded the output of this map.
+	pub(crate) fn extend(&mut self, later: SourceMap) {
+		self.rounds.extend(later.rounds);
+	}
+
+	/// Maps a byte range in the expanded source to the source the user wrote.
+	pub(crate) fn to_original(&self, span: Range<usize>) -> Range<usize> {
+		self.rounds.iter().rev().fold(span, |span, round| round.map(span))
+	}
+
+	/// Makes the spans of an error point at the source the user wrote. Errors in code that came out of a macro or constant say so.
+	pub(crate) fn remap(&self, error: Report) -> Report {
+		match error.downcast::<ErrorList>() {
+			Ok(list) => ErrorList::found_together(list.errors.into_iter().map(|e| self.remap_error(e)).collect()),
+			Err(e) => match e.downcast::<SpannedError>() {
+				Ok(e) => Report::new(self.remap_error(e)),
+				Err(e) => e,
+			},
+		}
+	}
+
+	pub(crate) fn remap_error(&self, mut error: SpannedError) -> SpannedError {
+		let mut generated = false;
+		for round in self.rounds.iter().rev() {
+			generated |= round.is_generated(error.span.start);
+			if round.is_included(error.span.start) {
+				let position = error.span.start;
+				if let Some(text) = self.included.iter().find(|t| t.expanded.start <= position && position < t.expanded.end) {
+					error.notes.push(included_note(&text.file, &text.source, text.start + position - text.expanded.start));
+				}
+			}
+			error.span = round.map(error.span);
+		}
+		if generated {
+			error.notes.push("The error is in the code this macro call or constant expands to.".to_string());
+		}
+		error
+	}
+}
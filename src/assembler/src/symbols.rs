@@ -0,0 +1,320 @@
+use std::ops::Range;
+
+use color_eyre::Result;
+use pest::iterators::Pair;
+
+use crate::diagnostics::trimmed_span;
+use crate::lexer::{lex, Rule};
+use crate::preprocessor::macro_expander::substitute_arguments;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+	Label,
+	Macro,
+	Constant,
+}
+
+
+/// A label, macro or constant defined in a program.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+	pub name: String,
+	pub kind: SymbolKind,
+	/// Where the name is written in the definition.
+	pub name_span: Range<usize>,
+	/// The whole definition, EG everything from `macro` to `end_macro:`.
+	pub span: Range<usize>,
+	/// The value of a constant, or the body of a macro.
+	pub value: String,
+	/// The parameters of a macro, including the leading `%`.
+	pub parameters: Vec<String>,
+}
+
+
+/// A call to a macro and the arguments it was called with.
+#[derive(Debug, Clone)]
+pub struct MacroCall {
+	/// The index of the macro in [`SymbolTable::symbols`], or None if no macro with that name exists.
+	pub symbol: Option<usize>,
+	pub span: Range<usize>,
+	pub arguments: Vec<String>,
+}
+
+
+/// Every symbol defined in a program and every place they are used, for editors.
+///
+/// Unlike [`crate::parser::parse`] this works on the source as written, without expanding macros, so every position points at the source.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+	pub symbols: Vec<Symbol>,
+	/// Every use of a symbol, as the index of the symbol and the span of the name.
+	pub references: Vec<(usize, Range<usize>)>,
+	pub calls: Vec<MacroCall>,
+}
+
+
+impl SymbolTable {
+	pub fn analyze(source: &str) -> Result<Self> {
+		let file = lex(source)?;
+		let mut table = Self::default();
+		let program = file.into_inner().find(|p| p.as_rule() == Rule::program).unwrap();
+		let mut calls = Vec::new();
+		for action in program.into_inner().filter(|a| a.as_rule() == Rule::action) {
+			let node = action.into_inner().next().unwrap();
+			match node.as_rule() {
+				Rule::label => {
+					let identifier = node.clone().into_inner().next().unwrap();
+					table.define(SymbolKind::Label, &identifier, trimmed_span(&node), String::new(), Vec::new());
+				}
+				Rule::constant => {
+					let mut inner = node.clone().into_inner();
+					let identifier = inner.next().unwrap();
+					let value = inner.next().unwrap().as_str().trim().to_string();
+					table.define(SymbolKind::Constant, &identifier, trimmed_span(&node), value, Vec::new());
+				}
+				Rule::full_macro => {
+					let mut inner = node.clone().into_inner();
+					let mut definition = inner.next().unwrap().into_inner();
+					let identifier = definition.next().unwrap();
+					let parameters = definition
+						.next()
+						.unwrap()
+						.into_inner()
+						.map(|p| p.as_str().trim().to_string())
+						.collect();
+					let body = inner.next().unwrap().as_str().to_string();
+					table.define(SymbolKind::Macro, &identifier, trimmed_span(&node), body, parameters);
+				}
+				Rule::macro_call => {
+					let mut inner = node.clone().into_inner();
+					let name = inner.next().unwrap().as_str().trim().to_string();
+					let arguments = inner
+						.next()
+						.unwrap()
+						.into_inner()
+						.map(|a| a.as_str().trim().to_string())
+						.filter(|a| !a.is_empty())
+						.collect();
+					calls.push((name, trimmed_span(&node), arguments));
+				}
+				_ => (),
+			}
+		}
+		for (name, span, arguments) in calls {
+			let symbol = table.symbols.iter().position(|s| s.kind == SymbolKind::Macro && s.name == name);
+			table.calls.push(MacroCall { symbol, span, arguments });
+		}
+		table.find_references(source);
+		Ok(table)
+	}
+
+	/// Finds the symbol whose definition or use contains the byte `offset`.
+	pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+		let contains = |span: &Range<usize>| span.start <= offset && offset <= span.end;
+		self.symbols
+			.iter()
+			.position(|s| contains(&s.name_span))
+			.or_else(|| self.references.iter().find(|(_, span)| contains(span)).map(|(i, _)| *i))
+	}
+
+	/// Every place the symbol at `index` is used, not including its definition.
+	pub fn references_to(&self, index: usize) -> impl Iterator<Item = &Range<usize>> {
+		self.references.iter().filter(move |(i, _)| *i == index).map(|(_, span)| span)
+	}
+
+	/// The macro call containing the byte `offset`.
+	pub fn call_at(&self, offset: usize) -> Option<&MacroCall> {
+		self.calls.iter().find(|c| c.span.start <= offset && offset <= c.span.end)
+	}
+
+	/// The body of the macro that `call` calls, with the arguments of the call filled in.
+	pub fn expand(&self, call: &MacroCall) -> Option<String> {
+		let symbol = &self.symbols[call.symbol?];
+		if symbol.parameters.len() != call.arguments.len() {
+			return None;
+		}
+		if symbol.parameters.is_empty() {
+			return Some(symbol.value.clone());
+		}
+		let parameters: Vec<&str> = symbol.parameters.iter().map(String::as_str).collect();
+		let arguments: Vec<&str> = call.arguments.iter().map(String::as_str).collect();
+		Some(substitute_arguments(&symbol.value, &parameters, &arguments))
+	}
+
+	fn define(&mut self, kind: SymbolKind, identifier: &Pair<Rule>, span: Range<usize>, value: String, parameters: Vec<String>) {
+		self.symbols.push(Symbol {
+			name: identifier.as_str().trim().to_string(),
+			kind,
+			name_span: trimmed_span(identifier),
+			span,
+			value,
+			parameters,
+		});
+	}
+
+	/// Finds every word in the source that names a symbol, skipping macro parameters.
+	fn find_references(&mut self, source: &str) {
+		for word in words(source) {
+			let name = &source[word.clone()];
+			if name.starts_with('%') {
+				continue;
+			}
+			if let Some(index) = self.symbols.iter().position(|s| s.name == name) {
+				if self.symbols[index].name_span != word {
+					self.references.push((index, word));
+				}
+			}
+		}
+	}
+}
+
+
+/// The identifiers and macro parameters in `text`, skipping comments, numbers and character and string literals.
+pub(crate) fn words(text: &str) -> Vec<Range<usize>> {
+	let bytes = text.as_bytes();
+	let mut words = Vec::new();
+	let mut i = 0;
+	while i < bytes.len() {
+		let rest = &text[i..];
+		if rest.starts_with("//") {
+			i += rest.find('\n').unwrap_or(rest.len());
+		} else if rest.starts_with("/*") {
+			i += rest.find("*/").map_or(rest.len(), |v| v + 2);
+		} else if rest.starts_with('\'') {
+			i += char_length(rest);
+		} else if rest.starts_with('"') {
+			i += string_length(rest);
+		} else if bytes[i] == b'%' || is_word_byte(bytes[i]) {
+			let start = i;
+			i += 1;
+			while i < bytes.len() && is_word_byte(bytes[i]) {
+				i += 1;
+			}
+			if !bytes[start].is_ascii_digit() {
+				words.push(start..i);
+			}
+		} else {
+			i += rest.chars().next().map_or(1, char::len_utf8);
+		}
+	}
+	words
+}
+
+
+fn is_word_byte(byte: u8) -> bool {
+	byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+
+/// The length of the character literal at the start of `rest`, including its quotes. Like the grammar, `'''` is a
+/// quote and `'\'` is a backslash. A quote that does not start a literal is one byte long.
+pub(crate) fn char_length(rest: &str) -> usize {
+	let inner = &rest[1..];
+	let escape = match inner.strip_prefix('\\') {
+		Some(e) if e.len() >= 3 && e.starts_with('x') && e.as_bytes()[1..3].iter().all(u8::is_ascii_hexdigit) => 4,
+		Some(e) if e.starts_with(['n', 't', 'r', '0', '\\', '"', '\'']) => 2,
+		_ => 0,
+	};
+	if escape > 0 && inner[escape..].starts_with('\'') {
+		return escape + 2;
+	}
+	match inner.chars().next() {
+		Some(c) if c != '\n' && inner[c.len_utf8()..].starts_with('\'') => c.len_utf8() + 2,
+		_ => 1,
+	}
+}
+
+
+/// The length of the string literal at the start of `rest`, including its quotes. Strings end at the line they are on.
+pub(crate) fn string_length(rest: &str) -> usize {
+	let mut escaped = false;
+	for (i, c) in rest.char_indices().skip(1) {
+		match c {
+			'\n' => return i,
+			'"' if !escaped => return i + 1,
+			_ => escaped = c == '\\' && !escaped,
+		}
+	}
+	rest.len()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+
+	const SOURCE: &str = "program:\nlimit = 10\nmacro show(%char, %times):\n\tldi %char -> out // limit\n\tldi %times -> reg4\nend_macro:\nlabel start:\nshow('a', limit)\nshow(\"start\", 2)\nstart\nmissing(1)\n";
+
+
+	#[test]
+	fn symbols_are_defined() {
+		let table = SymbolTable::analyze(SOURCE).unwrap();
+		let symbols: Vec<(&str, SymbolKind)> = table.symbols.iter().map(|s| (s.name.as_str(), s.kind)).collect();
+		assert_eq!(symbols, [("limit", SymbolKind::Constant), ("show", SymbolKind::Macro), ("start", SymbolKind::Label)]);
+
+		let limit = &table.symbols[0];
+		assert_eq!(&SOURCE[limit.name_span.clone()], "limit");
+		assert_eq!(&SOURCE[limit.span.clone()], "limit = 10");
+		assert_eq!(limit.value, "10");
+
+		let show = &table.symbols[1];
+		assert_eq!(show.parameters, ["%char", "%times"]);
+		assert!(SOURCE[show.span.clone()].starts_with("macro show(") && SOURCE[show.span.clone()].ends_with("end_macro:"));
+		assert_eq!(show.value, "\n\tldi %char -> out // limit\n\tldi %times -> reg4");
+
+		assert_eq!(&SOURCE[table.symbols[2].span.clone()], "label start:");
+	}
+
+
+	#[test]
+	fn references_skip_comments_literals_and_parameters() {
+		let table = SymbolTable::analyze(SOURCE).unwrap();
+		let at = |text: &str| SOURCE.find(text).unwrap();
+		let references: Vec<(&str, usize)> = table.references.iter().map(|(i, span)| (table.symbols[*i].name.as_str(), span.start)).collect();
+		assert_eq!(references, [("show", at("show('a'")), ("limit", at("limit)")), ("show", at("show(\"")), ("start", at("start\nmissing"))]);
+		assert_eq!(table.references_to(2).collect::<Vec<_>>(), [&(at("start\nmissing")..at("\nmissing"))]);
+
+		assert_eq!(table.symbol_at(at("limit =") + 2), Some(0));
+		assert_eq!(table.symbol_at(at("limit)")), Some(0));
+		assert_eq!(table.symbol_at(at("missing")), None);
+	}
+
+
+	#[test]
+	fn quotes_in_character_literals() {
+		for literal in ["'''", r"'\'", r"'\''", r"'\x41'", "'\"'", "'é'"] {
+			let source = format!("program:\nx = 1\nmacro m(%a, %b):\n\tldi %a -> out\n\tldi %b -> out\nend_macro:\nm({literal}, x)\n");
+			let table = SymbolTable::analyze(&source).unwrap();
+			assert_eq!(table.references_to(0).count(), 1, "{literal}");
+		}
+		// A quote that does not start a literal is skipped on its own.
+		assert_eq!(words("'a b"), [1..2, 3..4]);
+		assert_eq!(words("'\nb c"), [2..3, 4..5]);
+	}
+
+
+	#[test]
+	fn calls_are_found_and_expanded() {
+		let table = SymbolTable::analyze(SOURCE).unwrap();
+		assert_eq!(table.calls.len(), 3);
+		let call = table.call_at(SOURCE.find("'a'").unwrap()).unwrap();
+		assert_eq!(call.symbol, Some(1));
+		assert_eq!(call.arguments, ["'a'", "limit"]);
+		assert_eq!(&SOURCE[call.span.clone()], "show('a', limit)");
+		assert_eq!(table.expand(call).unwrap(), "\n\tldi 'a' -> out // limit\n\tldi limit -> reg4");
+
+		let missing = table.call_at(SOURCE.find("missing").unwrap()).unwrap();
+		assert_eq!(missing.symbol, None);
+		assert_eq!(table.expand(missing), None);
+		assert!(table.call_at(SOURCE.find("label").unwrap()).is_none());
+
+		// A call with the wrong number of arguments can not be expanded.
+		let wrong = MacroCall {
+			symbol: Some(1),
+			span: 0..0,
+			arguments: vec!["1".to_string()],
+		};
+		assert_eq!(table.expand(&wrong), None);
+	}
+}
@@ -0,0 +1,249 @@
+//! A front end for the assembly dialect used by the program component in Turing Complete.
+//!
+//! Every line of a program is one byte, which is the sum of the numbers and names on that line.
+//! `const NAME VALUE` defines a name, `label NAME` names the position of the next byte, and comments start with `#` or `//`.
+//! Names are case insensitive. Besides constants and labels, the names of this VM's instructions are predefined:
+//! - `add`, `sub`, `and`, `nand`, `or`, `nor`, `xor` and `xnor`.
+//! - `nop`, `jmp`, `jez`, `jnz`, `jgz`, `jlez`, `jgez`, `jlz` and `halt`.
+//! - `copy` (or `mov`), combined with a source from `from_reg0` to `from_reg5` or `from_in`, and a destination from `to_reg0` to `to_reg5` or `to_out`.
+//!
+//! A line with just a number loads that number into reg0, like it does in the regular assembly.
+use std::collections::HashMap;
+
+use bytecode_interpreter::run::{
+    Arithmetic, ARITHMETIC_PREFIX, Conditional, CONDITIONAL_PREFIX, FromStore, Halt, MOVE_PREFIX,
+    ToStore,
+};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+
+use crate::parser::SuccessfulParse;
+
+
+#[derive(Parser)]
+#[grammar = "./turing_complete.pest"] // relative to src
+struct Grammar;
+
+
+/// Assembles a program written in the Turing Complete dialect into the same bytecode [`crate::parser::parse`] produces.
+pub fn parse(program: &str) -> Result<SuccessfulParse> {
+	let file = Grammar::parse(Rule::file, program).map_err(|x| eyre!(x))?.next().unwrap();
+	let lines: Vec<Pair<Rule>> = file.into_inner().filter(|l| l.as_rule() == Rule::line).collect();
+
+	// Every instruction is one byte, so labels can be placed before anything is evaluated.
+	let mut names: HashMap<String, u8> = HashMap::new();
+	let mut labels = Vec::new();
+	let mut position = 0usize;
+	for line in &lines {
+		let node = match line.clone().into_inner().next() {
+			Some(v) => v,
+			None => continue,
+		};
+		match node.as_rule() {
+			Rule::label => {
+				let name = node.into_inner().next().unwrap().as_str();
+				if position > 255 {
+					return Err(eyre!("The label {name} points past the end of the largest possible program."));
+				}
+				define(&mut names, name, position as u8)?;
+				labels.push((name.to_string(), position as u8));
+			}
+			Rule::instruction => position += 1,
+			_ => (),
+		}
+	}
+
+	let mut instructions = Vec::new();
+	let mut source_lines = Vec::new();
+	for line in lines {
+		let line_number = line.as_span().start_pos().line_col().0 as u32;
+		let node = match line.into_inner().next() {
+			Some(v) => v,
+			None => continue,
+		};
+		match node.as_rule() {
+			Rule::constant => {
+				let mut inner = node.into_inner();
+				let name = inner.next().unwrap().as_str();
+				let value = evaluate(&names, inner.next().unwrap())?;
+				define(&mut names, name, value)?;
+			}
+			Rule::instruction => {
+				let mut byte = 0u8;
+				for value in node.into_inner() {
+					byte = byte.wrapping_add(evaluate(&names, value)?);
+				}
+				instructions.push(byte);
+				source_lines.push(line_number);
+			}
+			Rule::label => (),
+			_ => unreachable!(),
+		}
+	}
+	if instructions.len() > 255 {
+		return Err(eyre!("Programs cannot currently be longer 255 bytes, but this one is {} bytes.", instructions.len()));
+	}
+
+	let mut parsed = SuccessfulParse::from(Vec::new(), instructions, program.to_string());
+	parsed.labels = labels;
+	parsed.source_lines = source_lines;
+	Ok(parsed)
+}
+
+
+fn define(names: &mut HashMap<String, u8>, name: &str, value: u8) -> Result<()> {
+	let key = name.to_ascii_lowercase();
+	if names.contains_key(&key) || builtin(&key).is_some() {
+		return Err(eyre!("The name {name} is defined more than once."));
+	}
+	names.insert(key, value);
+	Ok(())
+}
+
+
+fn evaluate(names: &HashMap<String, u8>, value: Pair<Rule>) -> Result<u8> {
+	let as_str = value.as_str();
+	match value.as_rule() {
+		Rule::number => {
+			let negative = as_str.starts_with('-');
+			let digits = value.into_inner().next().unwrap();
+			let magnitude = match digits.as_rule() {
+				Rule::hex => i64::from_str_radix(&digits.as_str()[2..], 16),
+				Rule::bin => i64::from_str_radix(&digits.as_str()[2..], 2),
+				Rule::dec => digits.as_str().parse(),
+				_ => unreachable!(),
+			};
+			let number = match magnitude {
+				Ok(v) if negative => -v,
+				Ok(v) => v,
+				Err(_) => return Err(eyre!("The number {as_str} does not fit in a byte.")),
+			};
+			if !(-128..=255).contains(&number) {
+				return Err(eyre!("The number {as_str} does not fit in a byte."));
+			}
+			Ok(number as u8)
+		}
+		Rule::identifier => {
+			let key = as_str.to_ascii_lowercase();
+			match names.get(&key).copied().or_else(|| builtin(&key)) {
+				Some(v) => Ok(v),
+				None => Err(eyre!("Unknown identifier: {as_str}")),
+			}
+		}
+		_ => unreachable!(),
+	}
+}
+
+
+/// The value of the names that are defined in every program. `name` must be lower case.
+fn builtin(name: &str) -> Option<u8> {
+	let arithmetic = |op: u8| Some(ARITHMETIC_PREFIX << 6 | op);
+	let conditional = |op: u8| Some(CONDITIONAL_PREFIX << 6 | op);
+	match name {
+		"add" => arithmetic(Arithmetic::ADD),
+		"sub" => arithmetic(Arithmetic::SUB),
+		"and" => arithmetic(Arithmetic::AND),
+		"nand" => arithmetic(Arithmetic::NAND),
+		"or" => arithmetic(Arithmetic::OR),
+		"nor" => arithmetic(Arithmetic::NOR),
+		"xor" => arithmetic(Arithmetic::XOR),
+		"xnor" => arithmetic(Arithmetic::XNOR),
+
+		"nop" => conditional(Conditional::NOP),
+		"jmp" => conditional(Conditional::JMP),
+		"jez" => conditional(Conditional::JEZ),
+		"jnz" => conditional(Conditional::JNZ),
+		"jgz" => conditional(Conditional::JGZ),
+		"jlez" => conditional(Conditional::JLEZ),
+		"jgez" => conditional(Conditional::JGEZ),
+		"jlz" => conditional(Conditional::JLZ),
+		"halt" => Some(Halt::SUCCESS),
+
+		"copy" | "mov" => Some(MOVE_PREFIX << 6),
+		"from_in" => Some(FromStore::IN << 3),
+		"to_out" => Some(ToStore::OUT),
+		_ => {
+			if let Some(register) = name.strip_prefix("from_reg") {
+				register_number(register).map(|r| r << 3)
+			} else if let Some(register) = name.strip_prefix("to_reg") {
+				register_number(register)
+			} else {
+				None
+			}
+		}
+	}
+}
+
+
+fn register_number(register: &str) -> Option<u8> {
+	match register {
+		"0" | "1" | "2" | "3" | "4" | "5" => register.parse().ok(),
+		_ => None,
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use bytecode_interpreter::run::{FromStore, MOVE_PREFIX};
+
+	use super::parse;
+
+
+	#[test]
+	fn assembles_like_the_regular_assembly() {
+		let turing_complete = "# Jump over the halt.
+Skip
+JMP
+halt
+label skip
+const Limit 10
+limit
+COPY FROM_REG0 to_reg1
+1
+copy from_reg0 To_Reg2
+Add
+mov from_reg3 to_out
+end
+jez // Not taken, reg3 is 11.
+label end
+";
+		let myvm = "program:\nskip\nj\nhalt\nlabel skip:\n10\nmov 0 1\n1\nmov 0 2\nadd\nmov 3 out\nend\njez\nlabel end:\n";
+		let expected = crate::parser::parse(myvm).unwrap();
+		let parsed = parse(turing_complete).unwrap();
+		assert_eq!(parsed.program(), expected.program());
+		assert_eq!(parsed.labels(), [("skip".to_string(), 3), ("end".to_string(), 11)]);
+		assert_eq!(parsed.source_lines()[..3], [2, 3, 4]);
+	}
+
+
+	#[test]
+	fn lines_add_up_their_values() {
+		let parsed = parse("const five 5\nfive 0x10 0b1\nfrom_in to_reg4 copy\n-1\nlabel after\nafter\n").unwrap();
+		assert_eq!(parsed.program(), [22, MOVE_PREFIX << 6 | FromStore::IN << 3 | 4, 255, 3]);
+	}
+
+
+	#[test]
+	fn constants_must_be_defined_before_they_are_used() {
+		assert!(parse("limit\nconst limit 10\n").is_err());
+	}
+
+
+	#[test]
+	fn errors() {
+		for (program, message) in [
+			("256\n", "does not fit in a byte"),
+			("-129\n", "does not fit in a byte"),
+			("unknown\n", "Unknown identifier: unknown"),
+			("const a 1\nlabel A\nnop\n", "defined more than once"),
+			("const add 1\n", "defined more than once"),
+		] {
+			let error = parse(program).err().unwrap().to_string();
+			assert!(error.contains(message), "{program}: {error}");
+		}
+	}
+}
@@ -0,0 +1,433 @@
+//! Generates a Verilog model of the CPU with a program in its ROM, so programs can be run on an FPGA.
+//!
+//! The model implements the base instruction set and halting, with the semantics of [`bytecode_interpreter::run`].
+//! Every instruction takes one clock cycle, except moves from the input, which wait until input is available.
+//! Instructions from the instruction set extensions are not supported and stop the CPU with the `fault` output set.
+use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use bytecode_interpreter::run::{
+	Arithmetic, Conditional, Extension, FromStore, Input, Machine, Output, Queue, Step, ToStore,
+};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+
+use crate::parser::SuccessfulParse;
+
+
+/// Writes a synthesizable Verilog module called `myvm_cpu` with `program` in its ROM.
+pub fn to_verilog(program: &[u8]) -> String {
+	let mut rom = String::new();
+	for (address, instruction) in program.iter().enumerate() {
+		writeln!(rom, "            8'd{address}: instruction = 8'h{instruction:02x};").unwrap();
+	}
+	let alu = [
+		(Arithmetic::ADD, "reg1 + reg2"),
+		(Arithmetic::SUB, "reg1 - reg2"),
+		(Arithmetic::AND, "reg1 & reg2"),
+		(Arithmetic::NAND, "~(reg1 & reg2)"),
+		(Arithmetic::OR, "reg1 | reg2"),
+		(Arithmetic::NOR, "~(reg1 | reg2)"),
+		(Arithmetic::XOR, "reg1 ^ reg2"),
+		(Arithmetic::XNOR, "~(reg1 ^ reg2)"),
+	]
+	.iter()
+	.map(|(op, expression)| format!("            3'b{op:03b}: alu = {expression};\n"))
+	.collect::<String>();
+	let conditions = [
+		(Conditional::NOP, "1'b0"),
+		(Conditional::JMP, "1'b1"),
+		(Conditional::JEZ, "zero"),
+		(Conditional::JNZ, "!zero"),
+		(Conditional::JGZ, "!zero && !negative"),
+		(Conditional::JLEZ, "zero || negative"),
+		(Conditional::JGEZ, "!negative"),
+		(Conditional::JLZ, "negative"),
+	]
+	.iter()
+	.map(|(condition, expression)| format!("            3'b{condition:03b}: jump = {expression};\n"))
+	.collect::<String>();
+
+	format!(
+		r#"// Generated by my_vm.
+module myvm_cpu (
+    input wire clk,
+    // Resets the registers and starts the program from the beginning.
+    input wire reset,
+    // A byte of input is consumed on every rising clock edge where both in_valid and in_ready are high.
+    input wire [7:0] in_data,
+    input wire in_valid,
+    output wire in_ready,
+    // out_valid is high for one clock cycle for every byte the program outputs.
+    output reg [7:0] out_data,
+    output reg out_valid,
+    output reg halted,
+    output reg [7:0] exit_status,
+    // Set when the program runs an instruction the CPU does not support. The CPU stops.
+    output reg fault
+);
+    localparam PROGRAM_LENGTH = {length};
+
+    reg [7:0] registers [0:5];
+    reg [7:0] pc;
+    reg [7:0] instruction;
+    integer i;
+
+    always @(*) begin
+        case (pc)
+{rom}            default: instruction = 8'h00;
+        endcase
+    end
+
+    wire [1:0] kind = instruction[7:6];
+    wire [2:0] from = instruction[5:3];
+    wire [2:0] to = instruction[2:0];
+    // The operation of arithmetic instructions and the condition of conditional instructions.
+    wire [2:0] operation = instruction[2:0];
+    wire [7:0] reg0 = registers[0];
+    wire [7:0] reg1 = registers[1];
+    wire [7:0] reg2 = registers[2];
+    wire [7:0] reg3 = registers[3];
+    // Running past the end of the program halts it, like in the interpreter.
+    wire in_program = {{1'b0, pc}} < PROGRAM_LENGTH;
+    wire running = !reset && !halted && !fault && in_program;
+    wire reads_input = kind == 2'b10 && from == 3'd{from_in};
+    assign in_ready = running && reads_input;
+    wire [7:0] source = reads_input ? in_data : registers[from];
+
+    reg [7:0] alu;
+    always @(*) begin
+        case (operation)
+{alu}        endcase
+    end
+
+    // Conditionals compare reg3 with zero as a signed number.
+    wire zero = reg3 == 8'd0;
+    wire negative = reg3[7];
+    reg jump;
+    always @(*) begin
+        case (operation)
+{conditions}        endcase
+    end
+
+    always @(posedge clk) begin
+        out_valid <= 1'b0;
+        if (reset) begin
+            pc <= 8'd0;
+            halted <= 1'b0;
+            fault <= 1'b0;
+            exit_status <= 8'd0;
+            out_data <= 8'd0;
+            for (i = 0; i < 6; i = i + 1)
+                registers[i] <= 8'd0;
+        end else if (!halted && !fault) begin
+            if (!in_program) begin
+                halted <= 1'b1;
+            end else begin
+                case (kind)
+                    2'b00: begin
+                        registers[0] <= {{2'b00, instruction[5:0]}};
+                        pc <= pc + 8'd1;
+                        halted <= pc == 8'd255;
+                    end
+                    2'b01: begin
+                        if (from != 3'd0) begin
+                            fault <= 1'b1;
+                        end else if (jump) begin
+                            pc <= reg0;
+                        end else begin
+                            pc <= pc + 8'd1;
+                            halted <= pc == 8'd255;
+                        end
+                    end
+                    2'b10: begin
+                        if (from == 3'd7) begin
+                            // Moves from 0b111 halt the program, with the exit status from the target register.
+                            if (to <= 3'd5) begin
+                                halted <= 1'b1;
+                                exit_status <= registers[to];
+                            end else if (to == 3'd7) begin
+                                halted <= 1'b1;
+                            end else begin
+                                fault <= 1'b1;
+                            end
+                        end else if (to == 3'd7) begin
+                            fault <= 1'b1;
+                        end else if (!reads_input || in_valid) begin
+                            if (to == 3'd{to_out}) begin
+                                out_data <= source;
+                                out_valid <= 1'b1;
+                            end else begin
+                                registers[to] <= source;
+                            end
+                            pc <= pc + 8'd1;
+                            halted <= pc == 8'd255;
+                        end
+                    end
+                    2'b11: begin
+                        if (from != 3'd0) begin
+                            fault <= 1'b1;
+                        end else begin
+                            registers[3] <= alu;
+                            pc <= pc + 8'd1;
+                            halted <= pc == 8'd255;
+                        end
+                    end
+                endcase
+            end
+        end
+    end
+endmodule
+"#,
+		length = program.len(),
+		from_in = FromStore::IN,
+		to_out = ToStore::OUT,
+	)
+}
+
+
+/// Writes a testbench for the module from [`to_verilog`] that feeds the program its input and checks what it outputs.
+///
+/// The expected output and exit status come from running the program in the interpreter for at most `max_cycles` instructions.
+pub fn to_verilog_testbench(parsed: &SuccessfulParse, max_cycles: u64) -> Result<String> {
+	let (output, exit_status, cycles) = simulate(parsed.program(), parsed.input(), max_cycles)
+		.wrap_err("Could not work out what the program should output for the testbench.")?;
+	let input = parsed.input();
+
+	let mut initial = String::new();
+	for (i, byte) in input.iter().enumerate() {
+		writeln!(initial, "        input_bytes[{i}] = 8'h{byte:02x};").unwrap();
+	}
+	for (i, byte) in output.iter().enumerate() {
+		writeln!(initial, "        expected_output[{i}] = 8'h{byte:02x};").unwrap();
+	}
+	let exit_check = match exit_status {
+		Some(status) => format!(
+			r#"        if (!halted || exit_status != 8'd{status}) begin
+            $display("FAIL: expected the program to halt with exit status {status}, halted = %0d, exit status = %0d", halted, exit_status);
+            errors = errors + 1;
+        end
+"#
+		),
+		// The program did not halt within the cycles that are checked, so there is nothing to check.
+		None => String::new(),
+	};
+
+	Ok(format!(
+		r#"// Generated by my_vm. Runs the program for {cycles} clock cycles and checks its output against the interpreter.
+`timescale 1ns / 1ps
+module myvm_cpu_tb;
+    localparam INPUT_LENGTH = {input_length};
+    localparam OUTPUT_LENGTH = {output_length};
+
+    reg clk = 1'b0;
+    reg reset = 1'b1;
+    reg [7:0] input_bytes [0:{input_last}];
+    reg [7:0] expected_output [0:{output_last}];
+    integer input_position = 0;
+    integer output_position = 0;
+    integer errors = 0;
+
+    wire in_valid = input_position < INPUT_LENGTH;
+    wire [7:0] in_data = in_valid ? input_bytes[input_position] : 8'd0;
+    wire in_ready;
+    wire [7:0] out_data;
+    wire out_valid;
+    wire halted;
+    wire [7:0] exit_status;
+    wire fault;
+
+    myvm_cpu cpu (
+        .clk(clk),
+        .reset(reset),
+        .in_data(in_data),
+        .in_valid(in_valid),
+        .in_ready(in_ready),
+        .out_data(out_data),
+        .out_valid(out_valid),
+        .halted(halted),
+        .exit_status(exit_status),
+        .fault(fault)
+    );
+
+    always #5 clk = ~clk;
+
+    always @(posedge clk) begin
+        if (!reset) begin
+            if (in_valid && in_ready)
+                input_position <= input_position + 1;
+            if (out_valid) begin
+                if (output_position >= OUTPUT_LENGTH) begin
+                    $display("FAIL: unexpected output %h", out_data);
+                    errors = errors + 1;
+                end else if (out_data != expected_output[output_position]) begin
+                    $display("FAIL: output byte %0d was %h, expected %h", output_position, out_data, expected_output[output_position]);
+                    errors = errors + 1;
+                end
+                output_position <= output_position + 1;
+            end
+        end
+    end
+
+    initial begin
+{initial}        @(posedge clk);
+        #1 reset = 1'b0;
+        // One more cycle so the output of the last instruction is checked.
+        repeat ({check_cycles}) @(posedge clk);
+        #1;
+        if (fault) begin
+            $display("FAIL: the CPU ran an unsupported instruction at %0d", cpu.pc);
+            errors = errors + 1;
+        end
+        if (output_position != OUTPUT_LENGTH) begin
+            $display("FAIL: the program output %0d bytes, expected %0d", output_position, OUTPUT_LENGTH);
+            errors = errors + 1;
+        end
+{exit_check}        if (errors == 0)
+            $display("PASS");
+        $finish;
+    end
+endmodule
+"#,
+		input_length = input.len(),
+		output_length = output.len(),
+		// Verilog does not allow empty arrays.
+		input_last = input.len().max(1) - 1,
+		output_last = output.len().max(1) - 1,
+		check_cycles = cycles + 1,
+	))
+}
+
+
+/// Runs the program in the interpreter with `input` and returns its output, its exit status if it halted and how many instructions it executed.
+///
+/// Running out of input stops the simulation, just like the CPU waits forever for input that never comes.
+fn simulate(program: &[u8], input: &[u8], max_cycles: u64) -> Result<(Vec<u8>, Option<u8>, u64)> {
+	if program.len() > 255 {
+		return Err(eyre!("Programs cannot be longer than 255 bytes, but this one is {} bytes.", program.len()));
+	}
+	let input: Queue = Rc::new(RefCell::new(input.iter().copied().collect()));
+	let output: Queue = Rc::new(RefCell::new(VecDeque::new()));
+	let mut machine = Machine::new(program, Input::QUEUE(input), Output::QUEUE(output.clone()), Extension::NONE);
+	let mut exit_status = None;
+	let mut cycles = 0;
+	while cycles < max_cycles {
+		match machine.step()? {
+			Step::Running => cycles += 1,
+			Step::Halted(status) => {
+				cycles += 1;
+				exit_status = Some(status);
+				break;
+			}
+			Step::WaitingForInput => break,
+		}
+	}
+	let output: Vec<u8> = output.borrow().iter().copied().collect();
+	Ok((output, exit_status, cycles))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parser::parse;
+
+
+	const PORTS: [&str; 10] = ["clk", "reset", "in_data", "in_valid", "in_ready", "out_data", "out_valid", "halted", "exit_status", "fault"];
+
+
+	/// Assembles `source`, which is put after a `program:` line, with `input` as its input section.
+	fn assemble(input: &str, source: &str) -> SuccessfulParse {
+		let input = if input.is_empty() { String::new() } else { format!("input:\n{input}\n") };
+		parse(&format!("{input}program:\n{source}\n")).unwrap()
+	}
+
+
+	#[test]
+	fn rom_and_ports() {
+		let verilog = to_verilog(&[0x05, 0x87, 0xff]);
+		assert!(verilog.contains("\n            8'd0: instruction = 8'h05;\n            8'd1: instruction = 8'h87;\n            8'd2: instruction = 8'hff;\n            default: instruction = 8'h00;\n"));
+		assert!(verilog.contains("localparam PROGRAM_LENGTH = 3;"));
+		assert!(verilog.contains("3'b000: alu = reg1 + reg2;"));
+		assert!(verilog.contains("3'b100: jump = 1'b1;"));
+
+		let header = &verilog[verilog.find("module myvm_cpu (").unwrap()..verilog.find(");").unwrap()];
+		let ports: Vec<&str> = header
+			.lines()
+			.filter(|l| l.trim_start().starts_with("input") || l.trim_start().starts_with("output"))
+			.map(|l| l.trim_end_matches(',').rsplit(' ').next().unwrap())
+			.collect();
+		assert_eq!(ports, PORTS);
+
+		let testbench = to_verilog_testbench(&assemble("", "halt"), 100).unwrap();
+		for port in PORTS {
+			assert!(testbench.contains(&format!(".{port}({port})")), "{port}");
+		}
+	}
+
+
+	#[test]
+	fn empty_rom() {
+		let verilog = to_verilog(&[]);
+		assert!(verilog.contains("localparam PROGRAM_LENGTH = 0;"));
+		assert!(verilog.contains("        case (pc)\n            default: instruction = 8'h00;\n"));
+	}
+
+
+	#[test]
+	fn testbench_arrays() {
+		// Verilog does not allow empty arrays, so an empty input or output still gets one element.
+		let testbench = to_verilog_testbench(&assemble("", "halt"), 100).unwrap();
+		assert!(testbench.contains("localparam INPUT_LENGTH = 0;\n    localparam OUTPUT_LENGTH = 0;\n"));
+		assert!(testbench.contains("reg [7:0] input_bytes [0:0];\n    reg [7:0] expected_output [0:0];\n"));
+		assert!(!testbench.contains("input_bytes[0] ="));
+
+		let testbench = to_verilog_testbench(&assemble("1, 2, 3", "mov in out\nmov in out\nhalt"), 100).unwrap();
+		assert!(testbench.contains("localparam INPUT_LENGTH = 3;\n    localparam OUTPUT_LENGTH = 2;\n"));
+		assert!(testbench.contains("reg [7:0] input_bytes [0:2];\n    reg [7:0] expected_output [0:1];\n"));
+		assert!(testbench.contains("        input_bytes[2] = 8'h03;\n        expected_output[0] = 8'h01;\n        expected_output[1] = 8'h02;\n"));
+	}
+
+
+	#[test]
+	fn testbench_checks_the_exit_status() {
+		let testbench = to_verilog_testbench(&assemble("", "7\nmov 0 3\nhalt reg3"), 100).unwrap();
+		assert!(testbench.contains("if (!halted || exit_status != 8'd7) begin"));
+		// Three instructions, and one more cycle to check the last one.
+		assert!(testbench.contains("Runs the program for 3 clock cycles"));
+		assert!(testbench.contains("repeat (4) @(posedge clk);"));
+	}
+
+
+	#[test]
+	fn simulate_matches_the_interpreter() {
+		let parsed = assemble("'a', 'b'", "mov in 1\nmov in 2\nmov 2 out\nmov 1 out\nadd\nhalt reg3");
+		let (output, exit_status, cycles) = simulate(parsed.program(), parsed.input(), 100).unwrap();
+		assert_eq!(output, b"ba");
+		assert_eq!(exit_status, Some(b'a'.wrapping_add(b'b')));
+		assert_eq!(cycles, 6);
+	}
+
+
+	#[test]
+	fn simulation_stops_when_the_input_runs_out() {
+		let parsed = assemble("5", "mov in out\nmov in out\nhalt");
+		let (output, exit_status, cycles) = simulate(parsed.program(), parsed.input(), 100).unwrap();
+		assert_eq!((output, exit_status, cycles), (vec![5], None, 1));
+	}
+
+
+	#[test]
+	fn simulation_stops_after_max_cycles() {
+		let parsed = assemble("", "label again:\nmov 1 out\nagain\nj");
+		let (output, exit_status, cycles) = simulate(parsed.program(), parsed.input(), 10).unwrap();
+		assert_eq!(exit_status, None);
+		assert_eq!(cycles, 10);
+		assert_eq!(output, [0; 4]);
+		// Nothing is known about the exit status, so the testbench does not check it.
+		let testbench = to_verilog_testbench(&parsed, 10).unwrap();
+		assert!(!testbench.contains("exit_status != "));
+	}
+}
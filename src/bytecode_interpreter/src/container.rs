@@ -0,0 +1,252 @@
+use std::io::{Read, Write};
+
+use color_eyre::eyre::{eyre, Result};
+
+use crate::run::MAX_PROGRAM_LENGTH;
+
+/// The kinds of sections a [`Container`] can hold. Readers skip sections they do not know, so new kinds can be added without a new version.
+pub struct Section;
+
+#[allow(dead_code)]
+impl Section {
+    /// The bytecode of the program.
+    pub const CODE: u8 = 1;
+    /// The input the program gets when no other input is given.
+    pub const INPUT: u8 = 2;
+    /// Every label and the address it points to. Each entry is the address, the length of the name and the name in UTF-8.
+    pub const SYMBOLS: u8 = 3;
+    /// The source line every instruction came from. Each entry is the address followed by the line as a little endian u32.
+    pub const SOURCE_MAP: u8 = 4;
+}
+
+/// An assembled program together with everything needed to run and debug it.
+///
+/// A container starts with the magic bytes "MYVM", a version byte, the extensions the program needs and the number of sections.
+/// Every section is a kind byte from [`Section`], the length of its contents as a little endian u32 and then the contents.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Container {
+    /// The instruction set extensions the program needs, as [`crate::run::Extension`] flags.
+    pub extensions: u8,
+    pub code: Vec<u8>,
+    pub input: Vec<u8>,
+    pub symbols: Vec<(String, u8)>,
+    /// Pairs of an address and the source line the instruction at that address came from.
+    pub source_map: Vec<(u8, u32)>,
+}
+
+impl Container {
+    pub const MAGIC: [u8; 4] = *b"MYVM";
+    pub const VERSION: u8 = 1;
+
+    pub fn new(code: Vec<u8>) -> Self {
+        Self {
+            code,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `bytes` start with the header of a container, as opposed to being raw bytecode.
+    ///
+    /// The magic bytes are also the instructions `jnz reg1`, `jez reg4`, `jlez reg2` and `jnz reg1`, so raw bytecode that
+    /// starts with them is taken for a container. Such programs have to be put in a container to be loaded.
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(&Self::MAGIC)
+    }
+
+    /// Reads a container, or treats `bytes` as raw bytecode if they do not start with a container header.
+    pub fn load(bytes: &[u8]) -> Result<Self> {
+        if Self::is_container(bytes) {
+            Self::from_bytes(bytes)
+        } else {
+            check_length(bytes)?;
+            Ok(Self::new(bytes.to_vec()))
+        }
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::load(&bytes)
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        check_length(&self.code)?;
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut symbols = Vec::new();
+        for (name, address) in &self.symbols {
+            // Names longer than 255 bytes are cut short, the symbol table is only used for debugging.
+            let name = &name.as_bytes()[..name.len().min(255)];
+            symbols.push(*address);
+            symbols.push(name.len() as u8);
+            symbols.extend_from_slice(name);
+        }
+        let mut source_map = Vec::new();
+        for (address, line) in &self.source_map {
+            source_map.push(*address);
+            source_map.extend_from_slice(&line.to_le_bytes());
+        }
+        let mut sections = vec![(Section::CODE, self.code.as_slice())];
+        if !self.input.is_empty() {
+            sections.push((Section::INPUT, &self.input));
+        }
+        if !symbols.is_empty() {
+            sections.push((Section::SYMBOLS, &symbols));
+        }
+        if !source_map.is_empty() {
+            sections.push((Section::SOURCE_MAP, &source_map));
+        }
+
+        let mut bytes = Self::MAGIC.to_vec();
+        bytes.push(Self::VERSION);
+        bytes.push(self.extensions);
+        bytes.push(sections.len() as u8);
+        for (kind, contents) in sections {
+            bytes.push(kind);
+            bytes.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(contents);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader { bytes, position: 0 };
+        if reader.take(4)? != Self::MAGIC {
+            return Err(eyre!("This is not a program container, it does not start with \"MYVM\"."));
+        }
+        let version = reader.byte()?;
+        if version != Self::VERSION {
+            return Err(eyre!("The program container has version {version}, but only version {} is supported.", Self::VERSION));
+        }
+        let mut container = Self {
+            extensions: reader.byte()?,
+            ..Self::default()
+        };
+        let mut has_code = false;
+        for _ in 0..reader.byte()? {
+            let kind = reader.byte()?;
+            let length = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+            let contents = reader.take(length)?;
+            match kind {
+                Section::CODE => {
+                    container.code = contents.to_vec();
+                    has_code = true;
+                }
+                Section::INPUT => container.input = contents.to_vec(),
+                Section::SYMBOLS => {
+                    let mut symbols = ByteReader { bytes: contents, position: 0 };
+                    while !symbols.is_done() {
+                        let address = symbols.byte()?;
+                        let length = symbols.byte()? as usize;
+                        let name = String::from_utf8_lossy(symbols.take(length)?).into_owned();
+                        container.symbols.push((name, address));
+                    }
+                }
+                Section::SOURCE_MAP => {
+                    let mut entries = ByteReader { bytes: contents, position: 0 };
+                    while !entries.is_done() {
+                        let address = entries.byte()?;
+                        let line = u32::from_le_bytes(entries.take(4)?.try_into().unwrap());
+                        container.source_map.push((address, line));
+                    }
+                }
+                _ => (),
+            }
+        }
+        if !has_code {
+            return Err(eyre!("The program container does not have a code section."));
+        }
+        check_length(&container.code)?;
+        Ok(container)
+    }
+}
+
+/// Checks that the machine can run `code`, which it can not if it is longer than [`MAX_PROGRAM_LENGTH`].
+pub fn check_length(code: &[u8]) -> Result<()> {
+    if code.len() > MAX_PROGRAM_LENGTH {
+        return Err(eyre!("The program is {} bytes, but programs cannot be longer than {MAX_PROGRAM_LENGTH} bytes.", code.len()));
+    }
+    Ok(())
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        match self.bytes.get(self.position..self.position + length) {
+            Some(v) => {
+                self.position += length;
+                Ok(v)
+            }
+            None => Err(eyre!("The program container ends unexpectedly, it is probably truncated or corrupt.")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Container {
+        Container {
+            extensions: 0b101,
+            code: vec![1, 2, 3],
+            input: vec![4, 5],
+            symbols: vec![("start".to_string(), 0), ("loop".to_string(), 2)],
+            source_map: vec![(0, 1), (1, 70_000), (2, 3)],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let container = example();
+        let mut bytes = Vec::new();
+        container.write_to(&mut bytes).unwrap();
+        assert!(Container::is_container(&bytes));
+        assert_eq!(Container::read_from(&mut bytes.as_slice()).unwrap(), container);
+        let code_only = Container::new(vec![7; 255]);
+        assert_eq!(Container::load(&code_only.to_bytes()).unwrap(), code_only);
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let mut bytes = example().to_bytes();
+        bytes[6] += 1;
+        bytes.extend([200, 3, 0, 0, 0, 9, 9, 9]);
+        assert_eq!(Container::from_bytes(&bytes).unwrap(), example());
+    }
+
+    #[test]
+    fn raw_bytecode() {
+        assert_eq!(Container::load(&[1, 2, 3]).unwrap(), Container::new(vec![1, 2, 3]));
+        assert!(Container::load(&[0; 256]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_containers() {
+        let bytes = example().to_bytes();
+        assert!(Container::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut newer = bytes.clone();
+        newer[4] = Container::VERSION + 1;
+        assert!(Container::from_bytes(&newer).is_err());
+        assert!(Container::from_bytes(&[b'M', b'Y', b'V', b'M', Container::VERSION, 0, 0]).is_err());
+        assert!(Container::load(b"MYVM").is_err());
+        let too_long = Container::new(vec![0; 256]);
+        assert!(too_long.write_to(&mut Vec::new()).is_err());
+        assert!(Container::from_bytes(&too_long.to_bytes()).is_err());
+    }
+}
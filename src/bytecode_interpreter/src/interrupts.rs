@@ -0,0 +1,154 @@
+/// The devices that can interrupt the program. Each source is one bit in the interrupt mask and in the interrupt cause.
+pub struct InterruptSource;
+
+#[allow(dead_code)]
+impl InterruptSource {
+    /// Raised every time the timer has counted down the number of instructions it was set to.
+    pub const TIMER: u8 = 1 << 0;
+    /// Raised while there is input available that the program has not read yet.
+    pub const INPUT: u8 = 1 << 1;
+}
+
+/// Keeps track of which interrupts are enabled and pending, and where to go when one is taken.
+#[derive(Debug, Default)]
+pub struct InterruptController {
+    mask: u8,
+    vector: u8,
+    pending: u8,
+    timer_period: u8,
+    timer_count: u8,
+    /// The sources of the interrupt that is being handled.
+    cause: u8,
+    /// The address to return to once the interrupt handler is done. None outside of an interrupt handler.
+    return_address: Option<u8>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_mask(&mut self, mask: u8) {
+        self.mask = mask;
+    }
+
+    pub fn set_vector(&mut self, vector: u8) {
+        self.vector = vector;
+    }
+
+    /// Sets the number of instructions between timer interrupts. 0 stops the timer.
+    pub fn set_timer(&mut self, period: u8) {
+        self.timer_period = period;
+        self.timer_count = 0;
+    }
+
+    pub fn is_enabled(&self, source: u8) -> bool {
+        self.mask & source != 0
+    }
+
+    pub fn raise(&mut self, source: u8) {
+        self.pending |= source;
+    }
+
+    pub fn clear(&mut self, source: u8) {
+        self.pending &= !source;
+    }
+
+    pub fn cause(&self) -> u8 {
+        self.cause
+    }
+
+    /// Advances the timer by one instruction.
+    pub fn tick(&mut self) {
+        if self.timer_period == 0 {
+            return;
+        }
+        self.timer_count += 1;
+        if self.timer_count >= self.timer_period {
+            self.timer_count = 0;
+            self.raise(InterruptSource::TIMER);
+        }
+    }
+
+    /// Takes a pending interrupt if there is one that is enabled and we are not already handling an interrupt.
+    ///
+    /// Returns the address of the interrupt handler, `pc` is saved as the address to return to.
+    pub fn take(&mut self, pc: u8) -> Option<u8> {
+        let enabled = self.pending & self.mask;
+        if enabled == 0 || self.return_address.is_some() {
+            return None;
+        }
+        self.cause = enabled;
+        // The timer interrupt only fires once per period, while the input interrupt stays raised until the input is read.
+        self.clear(enabled & InterruptSource::TIMER);
+        self.return_address = Some(pc);
+        Some(self.vector)
+    }
+
+    /// Leaves the interrupt handler. Returns the address to continue at, or None if we are not in an interrupt handler.
+    pub fn return_from_interrupt(&mut self) -> Option<u8> {
+        self.cause = 0;
+        self.return_address.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masked_interrupts_are_not_taken() {
+        let mut controller = InterruptController::new();
+        controller.set_vector(20);
+        controller.raise(InterruptSource::TIMER);
+        assert_eq!(controller.take(5), None);
+        controller.set_mask(InterruptSource::INPUT);
+        assert_eq!(controller.take(5), None);
+        controller.set_mask(InterruptSource::TIMER);
+        assert_eq!(controller.take(5), Some(20));
+        assert_eq!(controller.cause(), InterruptSource::TIMER);
+    }
+
+    #[test]
+    fn timer_fires_every_period() {
+        let mut controller = InterruptController::new();
+        controller.set_mask(InterruptSource::TIMER);
+        controller.set_timer(3);
+        let mut fired = Vec::new();
+        for pc in 0..9 {
+            controller.tick();
+            if controller.take(pc).is_some() {
+                fired.push(pc);
+                controller.return_from_interrupt();
+            }
+        }
+        assert_eq!(fired, [2, 5, 8]);
+        controller.set_timer(0);
+        for pc in 0..9 {
+            controller.tick();
+            assert_eq!(controller.take(pc), None);
+        }
+    }
+
+    #[test]
+    fn interrupts_do_not_nest() {
+        let mut controller = InterruptController::new();
+        controller.set_mask(InterruptSource::TIMER | InterruptSource::INPUT);
+        controller.set_vector(30);
+        controller.raise(InterruptSource::INPUT);
+        assert_eq!(controller.take(7), Some(30));
+        controller.raise(InterruptSource::TIMER);
+        assert_eq!(controller.take(31), None);
+        assert_eq!(controller.return_from_interrupt(), Some(7));
+        assert_eq!(controller.cause(), 0);
+        // The input interrupt stays raised until the input is read.
+        assert_eq!(controller.take(7), Some(30));
+        assert_eq!(controller.cause(), InterruptSource::TIMER | InterruptSource::INPUT);
+    }
+
+    #[test]
+    fn return_outside_of_a_handler() {
+        let mut controller = InterruptController::new();
+        assert_eq!(controller.return_from_interrupt(), None);
+    }
+}
@@ -1 +1,5 @@
+pub mod container;
+pub mod interrupts;
+pub mod network;
 pub mod run;
+pub mod timing;
\ No newline at end of file
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::run::{Input, Machine, Output, Queue, Step};
+
+/// What a node in a [`Network`] was doing when the network stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Halted(u8),
+    WaitingForInput,
+    /// The network stopped because it ran for the maximum number of rounds.
+    Running,
+}
+
+/// The result of running one node of a [`Network`].
+#[derive(Debug)]
+pub struct NodeReport {
+    pub name: String,
+    /// Everything the node wrote to its output.
+    pub output: Vec<u8>,
+    pub state: NodeState,
+}
+
+struct Node<'a> {
+    name: String,
+    machine: Machine<'a>,
+    input: Queue,
+    output: Queue,
+    /// The nodes that receive everything this node outputs.
+    destinations: Vec<usize>,
+    written: Vec<u8>,
+    state: NodeState,
+}
+
+/// Several machines where the output of one machine can be fed into the input of others.
+///
+/// The network runs in rounds, where every node that has not halted executes one instruction per round in the order the nodes were added.
+/// This makes running a network deterministic.
+pub struct Network<'a> {
+    nodes: Vec<Node<'a>>,
+    extensions: u8,
+}
+
+impl<'a> Network<'a> {
+    pub fn new(extensions: u8) -> Self {
+        Self {
+            nodes: Vec::new(),
+            extensions,
+        }
+    }
+
+    /// Adds a node that starts out with `input` as its input. Returns the index of the node.
+    pub fn add_node(&mut self, name: &str, program: &'a [u8], input: &[u8]) -> usize {
+        let input: Queue = Rc::new(RefCell::new(input.iter().copied().collect()));
+        let output: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let machine = Machine::new(
+            program,
+            Input::QUEUE(input.clone()),
+            Output::QUEUE(output.clone()),
+            self.extensions,
+        );
+        self.nodes.push(Node {
+            name: name.to_string(),
+            machine,
+            input,
+            output,
+            destinations: Vec::new(),
+            written: Vec::new(),
+            state: NodeState::Running,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Finds the index of the node called `name`.
+    pub fn node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|n| n.name == name)
+    }
+
+    /// Feeds everything `from` outputs into the input of `to`.
+    pub fn connect(&mut self, from: usize, to: usize) -> Result<()> {
+        if from >= self.nodes.len() || to >= self.nodes.len() {
+            return Err(eyre!("Tried to connect node {from} to node {to}, but the network only has {} nodes.", self.nodes.len()));
+        }
+        self.nodes[from].destinations.push(to);
+        Ok(())
+    }
+
+    /// Runs the network until every node has halted or is waiting for input that will never come, or until `max_rounds` rounds have passed.
+    pub fn run(mut self, max_rounds: u64) -> Result<Vec<NodeReport>> {
+        for _ in 0..max_rounds {
+            let mut made_progress = false;
+            for i in 0..self.nodes.len() {
+                if let NodeState::Halted(_) = self.nodes[i].state {
+                    continue;
+                }
+                let node = &mut self.nodes[i];
+                let step = node
+                    .machine
+                    .step()
+                    .wrap_err_with(|| format!("Node \"{}\" failed.", node.name))?;
+                node.state = match step {
+                    Step::Running => {
+                        made_progress = true;
+                        NodeState::Running
+                    }
+                    Step::Halted(status) => {
+                        made_progress = true;
+                        NodeState::Halted(status)
+                    }
+                    Step::WaitingForInput => NodeState::WaitingForInput,
+                };
+                self.forward_output(i);
+            }
+            if !made_progress {
+                break;
+            }
+        }
+        Ok(self
+            .nodes
+            .into_iter()
+            .map(|n| NodeReport {
+                name: n.name,
+                output: n.written,
+                state: n.state,
+            })
+            .collect())
+    }
+
+    fn forward_output(&mut self, from: usize) {
+        let written: Vec<u8> = self.nodes[from].output.borrow_mut().drain(..).collect();
+        if written.is_empty() {
+            return;
+        }
+        for &to in &self.nodes[from].destinations {
+            self.nodes[to].input.borrow_mut().extend(&written);
+        }
+        self.nodes[from].written.extend(written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{Conditional, FromStore, Halt, ToStore, CONDITIONAL_PREFIX, MOVE_PREFIX};
+
+    const fn mov(from: u8, to: u8) -> u8 {
+        MOVE_PREFIX << 6 | from << 3 | to
+    }
+
+    /// Copies its input to its output forever.
+    const ECHO: [u8; 3] = [mov(FromStore::IN, ToStore::OUT), 0, CONDITIONAL_PREFIX << 6 | Conditional::JMP];
+
+    #[test]
+    fn pipeline_stops_when_every_node_waits() {
+        let writer = [1, mov(FromStore::REG0, ToStore::OUT), 2, mov(FromStore::REG0, ToStore::OUT), Halt::with_status_from(ToStore::REG0)];
+        let mut network = Network::new(0);
+        let a = network.add_node("a", &writer, &[]);
+        let b = network.add_node("b", &ECHO, &[]);
+        let c = network.add_node("c", &ECHO, &[9]);
+        network.connect(a, b).unwrap();
+        network.connect(b, c).unwrap();
+        let reports = network.run(u64::MAX).unwrap();
+        assert_eq!(reports[0].state, NodeState::Halted(2));
+        assert_eq!(reports[0].output, [1, 2]);
+        assert_eq!(reports[1].state, NodeState::WaitingForInput);
+        assert_eq!(reports[1].output, [1, 2]);
+        assert_eq!(reports[2].state, NodeState::WaitingForInput);
+        assert_eq!(reports[2].output, [9, 1, 2]);
+    }
+
+    #[test]
+    fn nodes_take_turns() {
+        // Both nodes write to c in the same rounds, so their bytes alternate in the order the nodes were added.
+        let first = [1, mov(FromStore::REG0, ToStore::OUT), 2, mov(FromStore::REG0, ToStore::OUT)];
+        let second = [3, mov(FromStore::REG0, ToStore::OUT), 4, mov(FromStore::REG0, ToStore::OUT)];
+        let mut network = Network::new(0);
+        let a = network.add_node("a", &first, &[]);
+        let b = network.add_node("b", &second, &[]);
+        let c = network.add_node("c", &ECHO, &[]);
+        network.connect(a, c).unwrap();
+        network.connect(b, c).unwrap();
+        let reports = network.run(u64::MAX).unwrap();
+        assert_eq!(reports[2].output, [1, 3, 2, 4]);
+        let names: Vec<&str> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn stops_after_max_rounds() {
+        let forever = [0, CONDITIONAL_PREFIX << 6 | Conditional::JMP];
+        let mut network = Network::new(0);
+        network.add_node("loop", &forever, &[]);
+        let reports = network.run(100).unwrap();
+        assert_eq!(reports[0].state, NodeState::Running);
+    }
+
+    #[test]
+    fn connecting_unknown_nodes() {
+        let mut network = Network::new(0);
+        let a = network.add_node("a", &ECHO, &[]);
+        assert_eq!(network.node("a"), Some(a));
+        assert_eq!(network.node("b"), None);
+        assert!(network.connect(a, 1).is_err());
+    }
+}
@@ -1,7 +1,15 @@
 use color_eyre::eyre::{eyre, Result};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::hint::unreachable_unchecked;
 use std::io::{Read, Stdin, Stdout, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+use crate::interrupts::{InterruptController, InterruptSource};
+use crate::timing::TimingModel;
 
 macro_rules! unreachable_unsafe {
     () => {{
@@ -72,6 +80,100 @@ impl Arithmetic {
     pub const XNOR: u8 = 0b_111;
 }
 
+/// Arithmetic instructions that live in the encodings where the middle three bits of an arithmetic instruction are not zero.
+///
+/// These are only available when the [`Extension::EXTENDED_ALU`] extension is enabled.
+/// Just like the regular arithmetic instructions they read register 1 and register 2 and store their result in register 3.
+pub struct ExtendedArithmetic;
+
+#[allow(dead_code)]
+impl ExtendedArithmetic {
+    /// Shift register 1 left by register 2 bits. Shifting by 8 or more bits results in 0.
+    pub const SHL: u8 = 0b_001_000;
+    /// Logically shift register 1 right by register 2 bits. Shifting by 8 or more bits results in 0.
+    pub const SHR: u8 = 0b_001_001;
+    /// Arithmetically shift register 1 right by register 2 bits, preserving its sign.
+    pub const ASR: u8 = 0b_001_010;
+    /// Rotate register 1 left by register 2 bits.
+    pub const ROL: u8 = 0b_001_011;
+    /// Rotate register 1 right by register 2 bits.
+    pub const ROR: u8 = 0b_001_100;
+    /// The low byte of register 1 * register 2.
+    pub const MUL: u8 = 0b_010_000;
+    /// The high byte of register 1 * register 2, treating both as unsigned.
+    pub const MULH: u8 = 0b_010_001;
+    /// Register 1 + 1.
+    pub const INC: u8 = 0b_011_000;
+    /// Register 1 - 1.
+    pub const DEC: u8 = 0b_011_001;
+}
+
+/// Stops the program. Halting uses the move encodings with the source 0b111, which is not a valid source for moving.
+///
+/// The last three bits select the register that holds the exit status of the program.
+pub struct Halt;
+
+#[allow(dead_code)]
+impl Halt {
+    /// Halt with the exit status 0.
+    pub const SUCCESS: u8 = MOVE_PREFIX << 6 | FromStore::UNKNOWN << 3 | ToStore::UNKNOWN;
+
+    /// Halt with the exit status stored in `register`.
+    pub const fn with_status_from(register: u8) -> u8 {
+        MOVE_PREFIX << 6 | FromStore::UNKNOWN << 3 | register
+    }
+}
+
+/// Instructions that control interrupts. They live in the top of the arithmetic encoding space, IE 0b_11_111_xxx.
+///
+/// These are only available when the [`Extension::INTERRUPTS`] extension is enabled.
+/// When an interrupt is taken the address of the next instruction is saved, further interrupts are held back and execution continues at the interrupt vector.
+pub struct Interrupt;
+
+#[allow(dead_code)]
+impl Interrupt {
+    /// Set the interrupt mask to register 0. Each bit enables one [`InterruptSource`], 0 disables all interrupts.
+    pub const IMASK: u8 = 0b_111_000;
+    /// Set the address of the interrupt handler to register 0.
+    pub const IVEC: u8 = 0b_111_001;
+    /// Raise a timer interrupt every register 0 instructions. 0 stops the timer.
+    pub const ITIMER: u8 = 0b_111_010;
+    /// Return from the interrupt handler to the saved address.
+    pub const RETI: u8 = 0b_111_011;
+    /// Store the sources of the interrupt that is being handled in register 3.
+    pub const ICAUSE: u8 = 0b_111_100;
+}
+
+/// Optional extensions to the instruction set. Extensions are combined by or-ing them together.
+pub struct Extension;
+
+#[allow(dead_code)]
+impl Extension {
+    pub const NONE: u8 = 0;
+    /// Enables the instructions in [`ExtendedArithmetic`].
+    pub const EXTENDED_ALU: u8 = 1 << 0;
+    /// Enables conditional instructions that test something else than register 3. See [`ConditionalSource`].
+    pub const REGISTER_CONDITIONALS: u8 = 1 << 1;
+    /// Enables the instructions in [`Interrupt`].
+    pub const INTERRUPTS: u8 = 1 << 2;
+
+    /// The extensions that `instruction` needs to run.
+    pub fn required_by(instruction: u8) -> u8 {
+        let middle = instruction & 0b0011_1000;
+        match instruction & 0b1100_0000 {
+            InstructionType::ARITHMETIC if middle == 0b0011_1000 => Self::INTERRUPTS,
+            InstructionType::ARITHMETIC if middle != 0 => Self::EXTENDED_ALU,
+            InstructionType::CONDITIONAL if middle != 0 => Self::REGISTER_CONDITIONALS,
+            _ => Self::NONE,
+        }
+    }
+
+    /// The extensions that every instruction in `program` needs together.
+    pub fn required_by_program(program: &[u8]) -> u8 {
+        program.iter().fold(Self::NONE, |extensions, &instruction| extensions | Self::required_by(instruction))
+    }
+}
+
 pub struct Conditional;
 
 #[allow(dead_code)]
@@ -85,11 +187,34 @@ impl Conditional {
     pub const JGEZ: u8 = 0b_011;
     pub const JLZ: u8 = 0b_111;
 }
+
+/// Selects what a conditional instruction tests. It is stored in the middle three bits of a conditional instruction.
+///
+/// Everything other than [`ConditionalSource::REG3`] is only available when the [`Extension::REGISTER_CONDITIONALS`] extension is enabled.
+pub struct ConditionalSource;
+
+#[allow(dead_code)]
+impl ConditionalSource {
+    pub const REG3: u8 = 0b_000;
+    pub const REG1: u8 = 0b_001;
+    pub const REG2: u8 = 0b_010;
+    pub const REG4: u8 = 0b_011;
+    pub const REG5: u8 = 0b_100;
+    pub const REG0: u8 = 0b_101;
+    /// Compare register 1 with register 2 as signed numbers. EG JGZ jumps if register 1 > register 2.
+    pub const COMPARE: u8 = 0b_110;
+    /// Compare register 1 with register 2 as unsigned numbers.
+    pub const COMPARE_UNSIGNED: u8 = 0b_111;
+}
+/// A queue of bytes that can be shared between the output of one machine and the input of another.
+pub type Queue = Rc<RefCell<VecDeque<u8>>>;
+
 #[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum Output<'a> {
     STDOUT(Stdout),
     FILE(File),
     ARRAY(&'a mut [u8]),
+    QUEUE(Queue),
 }
 
 impl Write for Output<'_> {
@@ -98,6 +223,10 @@ impl Write for Output<'_> {
             Self::STDOUT(s) => s.write(buf),
             Self::FILE(f) => f.write(buf),
             Self::ARRAY(a) => a.write(buf),
+            Self::QUEUE(q) => {
+                q.borrow_mut().extend(buf);
+                Ok(buf.len())
+            }
         }
     }
 
@@ -106,6 +235,7 @@ impl Write for Output<'_> {
             Self::STDOUT(s) => s.flush(),
             Self::FILE(f) => f.flush(),
             Self::ARRAY(a) => a.flush(),
+            Self::QUEUE(_) => Ok(()),
         }
     }
 }
@@ -114,6 +244,12 @@ pub enum Input<'a> {
     STDIN(Stdin),
     FILE(File),
     ARRAY(&'a [u8]),
+    /// Bytes sent from another thread, EG a thread that reads STDIN in the background.
+    ///
+    /// Unlike STDIN this can be checked for available bytes without blocking, which input interrupts need.
+    CHANNEL(Receiver<u8>),
+    /// Bytes written by another machine. Unlike the other inputs, running out of bytes makes the machine wait for more instead of failing.
+    QUEUE(Queue),
 }
 
 impl Read for Input<'_> {
@@ -122,11 +258,59 @@ impl Read for Input<'_> {
             Self::STDIN(s) => s.read(buf),
             Self::FILE(f) => f.read(buf),
             Self::ARRAY(a) => a.read(buf),
+            Self::CHANNEL(c) => {
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+                // The sender hanging up means we have reached the end of the input.
+                match c.recv() {
+                    Ok(v) => buf[0] = v,
+                    Err(_) => return Ok(0),
+                }
+                Ok(1)
+            }
+            Self::QUEUE(q) => {
+                let mut q = q.borrow_mut();
+                let mut read = 0;
+                while read < buf.len() {
+                    match q.pop_front() {
+                        Some(v) => buf[read] = v,
+                        None => break,
+                    }
+                    read += 1;
+                }
+                Ok(read)
+            }
         }
     }
 }
 
 impl Input<'_> {
+    /// Reads the next byte if one is available without blocking.
+    ///
+    /// STDIN can not be checked without blocking, so it never has any bytes available. Use [`Input::CHANNEL`] instead.
+    fn poll(&mut self) -> Result<Option<u8>> {
+        match self {
+            Self::STDIN(_) => Ok(None),
+            Self::FILE(_) | Self::ARRAY(_) | Self::QUEUE(_) => {
+                let mut buf = [0u8; 1];
+                let read = self.read(&mut buf)?;
+                Ok(if read == 0 { None } else { Some(buf[0]) })
+            }
+            Self::CHANNEL(c) => match c.try_recv() {
+                Ok(v) => Ok(Some(v)),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None),
+            },
+        }
+    }
+
+    fn is_empty_queue(&self) -> bool {
+        match self {
+            Self::QUEUE(q) => q.borrow().is_empty(),
+            _ => false,
+        }
+    }
+
     fn next(&mut self) -> Result<u8> {
         let mut buf = [0u8; 1];
         let read = self.read(&mut buf)?;
@@ -141,40 +325,185 @@ impl Input<'_> {
 }
 
 macro_rules! increment_pc {
-    ($pc: ident) => {{
-        let new_val = $pc.checked_add(1);
+    ($machine: ident) => {{
+        let new_val = $machine.pc.checked_add(1);
         if new_val == None {
-            return Ok(());
+            return Ok($machine.halt(0));
         }
-        $pc = new_val.unwrap();
+        $machine.pc = new_val.unwrap();
     }};
 }
 
 /// This program is an implementation of an emulator for a custom CPU architecture. It is loosely based on the OVERTURE architecture from the Turing Complete programming video game.
-pub fn interpret(program: &[u8], mut input: Input, mut output: Output) -> Result<()> {
-    assert!(
-        program.len() < 256,
-        "Programs cannot currently be longer 255 bytes."
-    );
-    let mut registers = [0u8; 6];
-    let mut pc = 0u8;
-    loop {
+///
+/// Returns the exit status of the program. Programs that run off the end of the program exit with the status 0, other programs choose their exit status with a [`Halt`] instruction.
+pub fn interpret(program: &[u8], input: Input, output: Output) -> Result<u8> {
+    interpret_with_extensions(program, input, output, Extension::NONE)
+}
+
+/// Same as [`interpret`], but with the instruction set extensions in `extensions` enabled.
+pub fn interpret_with_extensions(
+    program: &[u8],
+    input: Input,
+    output: Output,
+    extensions: u8,
+) -> Result<u8> {
+    Machine::new(program, input, output, extensions).run()
+}
+
+/// The longest program a [`Machine`] can run.
+pub const MAX_PROGRAM_LENGTH: usize = 255;
+
+/// What happened during a call to [`Machine::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// An instruction was executed and the program can keep going.
+    Running,
+    /// The program has stopped with the given exit status.
+    Halted(u8),
+    /// The program tried to read from an empty [`Input::QUEUE`]. Stepping again retries the instruction.
+    WaitingForInput,
+}
+
+/// The state of a running program, which can be executed one instruction at a time.
+pub struct Machine<'a> {
+    program: Cow<'a, [u8]>,
+    input: Input<'a>,
+    output: Output<'a>,
+    extensions: u8,
+    registers: [u8; 6],
+    pc: u8,
+    interrupts: InterruptController,
+    /// A byte of input that has been read to check for an input interrupt, but that the program has not consumed yet.
+    pending_input: Option<u8>,
+    exit_status: Option<u8>,
+    timing: TimingModel,
+    ticks: u64,
+    instructions_executed: u64,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: &'a [u8], input: Input<'a>, output: Output<'a>, extensions: u8) -> Self {
+        Self::with_program(Cow::Borrowed(program), input, output, extensions)
+    }
+
+    /// Same as [`Machine::new`], but the machine owns the program, so it does not borrow anything if its input and output
+    /// do not either.
+    pub fn new_owned(program: Vec<u8>, input: Input<'a>, output: Output<'a>, extensions: u8) -> Self {
+        Self::with_program(Cow::Owned(program), input, output, extensions)
+    }
+
+    fn with_program(program: Cow<'a, [u8]>, input: Input<'a>, output: Output<'a>, extensions: u8) -> Self {
+        assert!(
+            program.len() <= MAX_PROGRAM_LENGTH,
+            "Programs cannot currently be longer 255 bytes."
+        );
+        Self {
+            program,
+            input,
+            output,
+            extensions,
+            registers: [0u8; 6],
+            pc: 0,
+            interrupts: InterruptController::new(),
+            pending_input: None,
+            exit_status: None,
+            timing: TimingModel::default(),
+            ticks: 0,
+            instructions_executed: 0,
+        }
+    }
+
+    /// Sets how many ticks each instruction takes. See [`Machine::ticks`].
+    pub fn set_timing_model(&mut self, timing: TimingModel) {
+        self.timing = timing;
+    }
+
+    /// How many ticks the program has taken so far according to its [`TimingModel`].
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    pub fn registers(&self) -> [u8; 6] {
+        self.registers
+    }
+
+    pub fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    /// The exit status of the program, or None if it has not halted yet.
+    pub fn exit_status(&self) -> Option<u8> {
+        self.exit_status
+    }
+
+    /// Runs the program until it halts. Returns its exit status.
+    ///
+    /// Waiting for input is treated as running out of input, since nothing else can fill the input while we are running.
+    pub fn run(&mut self) -> Result<u8> {
+        loop {
+            match self.step()? {
+                Step::Running => (),
+                Step::Halted(status) => return Ok(status),
+                Step::WaitingForInput => {
+                    return Err(eyre!(
+                        "There were not enough bytes in input to satisfy the program."
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Counts an executed instruction that took `ticks` ticks.
+    fn retire(&mut self, ticks: u64) {
+        self.instructions_executed += 1;
+        self.ticks += ticks;
+    }
+
+    fn halt(&mut self, status: u8) -> Step {
+        self.exit_status = Some(status);
+        Step::Halted(status)
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Step> {
+        if let Some(status) = self.exit_status {
+            return Ok(Step::Halted(status));
+        }
+        if self.extensions & Extension::INTERRUPTS != 0 {
+            if self.pending_input.is_none() && self.interrupts.is_enabled(InterruptSource::INPUT) {
+                self.pending_input = self.input.poll()?;
+            }
+            if self.pending_input.is_some() {
+                self.interrupts.raise(InterruptSource::INPUT);
+            }
+            if let Some(handler) = self.interrupts.take(self.pc) {
+                self.pc = handler;
+            }
+            self.interrupts.tick();
+        }
+        let pc = self.pc;
         // The first two bits in an instructions tells us the instructions type:
         // 00 - LOAD LITERAL
         // 01 - CONDITIONAL
         // 10 - MOVE
         // 11 - ARITHMETIC
-        let current_instruction = match program.get(pc as usize) {
+        let current_instruction = match self.program.get(self.pc as usize) {
             Some(v) => *v,
-            None => return Ok(()),
+            None => return Ok(self.halt(0)),
         };
         // Isolate instruction type:
         let instruction_type = 0b_11_00_00_00 & current_instruction;
         let body = 0b_00_11_11_11 & current_instruction;
         match instruction_type {
             InstructionType::ARITHMETIC => {
-                let reg1 = registers[1];
-                let reg2 = registers[2];
+                self.retire(self.timing.arithmetic);
+                let reg1 = self.registers[1];
+                let reg2 = self.registers[2];
 
                 let reg3 = match body {
                     Arithmetic::ADD => reg1.wrapping_add(reg2),
@@ -185,51 +514,115 @@ pub fn interpret(program: &[u8], mut input: Input, mut output: Output) -> Result
                     Arithmetic::NOR => !(reg1 | reg2),
                     Arithmetic::XOR => reg1 ^ reg2,
                     Arithmetic::XNOR => !(reg1 ^ reg2),
-                    _ => return Err(eyre!("Bad arithmetic instruction at instruction number {pc}. Instruction should be of the form: 0b_00_000_xxx. IE the middle three bits should be 0 but in this case they were not. The bad instruction was {current_instruction:#010b}")),
+                    _ if self.extensions & Extension::INTERRUPTS != 0 && body & 0b_111_000 == 0b_111_000 => {
+                        match body {
+                            Interrupt::IMASK => self.interrupts.set_mask(self.registers[0]),
+                            Interrupt::IVEC => self.interrupts.set_vector(self.registers[0]),
+                            Interrupt::ITIMER => self.interrupts.set_timer(self.registers[0]),
+                            Interrupt::RETI => match self.interrupts.return_from_interrupt() {
+                                Some(v) => {
+                                    self.pc = v;
+                                    return Ok(Step::Running);
+                                }
+                                None => return Err(eyre!("Tried to return from an interrupt outside of an interrupt handler at instruction number {pc}.")),
+                            },
+                            Interrupt::ICAUSE => self.registers[3] = self.interrupts.cause(),
+                            _ => return Err(eyre!("Bad interrupt instruction at instruction number {pc}. The instruction {current_instruction:#010b} does not belong to the interrupt instructions.")),
+                        }
+                        increment_pc!(self);
+                        return Ok(Step::Running);
+                    }
+                    _ if self.extensions & Extension::EXTENDED_ALU != 0 => match body {
+                        ExtendedArithmetic::SHL => reg1.checked_shl(reg2 as u32).unwrap_or(0),
+                        ExtendedArithmetic::SHR => reg1.checked_shr(reg2 as u32).unwrap_or(0),
+                        ExtendedArithmetic::ASR => ((reg1 as i8) >> reg2.min(7)) as u8,
+                        ExtendedArithmetic::ROL => reg1.rotate_left(reg2 as u32),
+                        ExtendedArithmetic::ROR => reg1.rotate_right(reg2 as u32),
+                        ExtendedArithmetic::MUL => reg1.wrapping_mul(reg2),
+                        ExtendedArithmetic::MULH => ((reg1 as u16 * reg2 as u16) >> 8) as u8,
+                        ExtendedArithmetic::INC => reg1.wrapping_add(1),
+                        ExtendedArithmetic::DEC => reg1.wrapping_sub(1),
+                        _ => return Err(eyre!("Bad extended arithmetic instruction at instruction number {pc}. The instruction {current_instruction:#010b} does not belong to the extended ALU.")),
+                    },
+                    _ => return Err(eyre!("Bad arithmetic instruction at instruction number {pc}. Instruction should be of the form: 0b_00_000_xxx. IE the middle three bits should be 0 but in this case they were not. The bad instruction was {current_instruction:#010b}. Instructions with non zero middle bits require the extended ALU extension.")),
                 };
-                registers[3] = reg3;
-                increment_pc!(pc);
-                continue;
+                self.registers[3] = reg3;
+                increment_pc!(self);
             }
             InstructionType::CONDITIONAL => {
-                let reg3 = registers[3] as i8;
-                let should_jump = match body {
+                self.retire(self.timing.conditional);
+                // Imagine the instruction looks like this:
+                // 01 000 111
+                // Here 01 is the instruction type, 000 is what we are testing and 111 is the condition
+                let source = (body & 0b0011_1000) >> 3;
+                let condition = body & 0b0000_0111;
+                // How the tested value compares to zero, or how register 1 compares to register 2.
+                let ordering = match source {
+                    ConditionalSource::REG3 => (self.registers[3] as i8).cmp(&0),
+                    _ if self.extensions & Extension::REGISTER_CONDITIONALS == 0 => return Err(eyre!("Bad conditional instruction at instruction number {pc}. Instruction should be of the form: 0b_01_000_xxx. IE the middle three bits should be 0 but in this case they were not. The bad instruction was {current_instruction:#010b}. Instructions with non zero middle bits require the register conditionals extension.")),
+                    ConditionalSource::REG0 => (self.registers[0] as i8).cmp(&0),
+                    ConditionalSource::REG1 => (self.registers[1] as i8).cmp(&0),
+                    ConditionalSource::REG2 => (self.registers[2] as i8).cmp(&0),
+                    ConditionalSource::REG4 => (self.registers[4] as i8).cmp(&0),
+                    ConditionalSource::REG5 => (self.registers[5] as i8).cmp(&0),
+                    ConditionalSource::COMPARE => (self.registers[1] as i8).cmp(&(self.registers[2] as i8)),
+                    ConditionalSource::COMPARE_UNSIGNED => self.registers[1].cmp(&self.registers[2]),
+                    _ => unsafe { unreachable_unchecked() },
+                };
+                let should_jump = match condition {
                     Conditional::NOP => false,
                     Conditional::JMP => true,
-                    Conditional::JEZ => reg3 == 0,
-                    Conditional::JNZ => reg3 != 0,
-                    Conditional::JGZ => reg3 > 0,
-                    Conditional::JLEZ => reg3 <= 0,
-                    Conditional::JGEZ => reg3 >= 0,
-                    Conditional::JLZ => reg3 < 0,
-                    _ => return Err(eyre!("Bad conditional instruction at instruction number {pc}. Instruction should be of the form: 0b_01_000_xxx. IE the middle three bits should be 0 but in this case they were not. The bad instruction was {current_instruction:#010b}")),
+                    Conditional::JEZ => ordering.is_eq(),
+                    Conditional::JNZ => ordering.is_ne(),
+                    Conditional::JGZ => ordering.is_gt(),
+                    Conditional::JLEZ => ordering.is_le(),
+                    Conditional::JGEZ => ordering.is_ge(),
+                    Conditional::JLZ => ordering.is_lt(),
+                    _ => unsafe { unreachable_unchecked() },
                 };
                 if should_jump {
-                    pc = registers[0];
+                    self.ticks += self.timing.taken_jump;
+                    self.pc = self.registers[0];
                 } else {
-                    increment_pc!(pc);
+                    increment_pc!(self);
                 }
-                continue;
             }
             InstructionType::MOVE => {
                 // Imagine the instruction looks like this:
                 // 00 000 111
                 // Here 00 is the instruction type, 000 is the register we are copying from and 111 is the target
-                let from = (current_instruction & 0b_00_111_000) >> 3;
-                let to = current_instruction & 0b_00_000_111;
+                let from = (current_instruction & 0b0011_1000) >> 3;
+                let to = current_instruction & 0b0000_0111;
                 let from_target = match from {
-                    FromStore::REG0..=FromStore::REG5 => registers[from as usize],
-                    FromStore::IN => input.next()?,
+                    FromStore::REG0..=FromStore::REG5 => self.registers[from as usize],
+                    FromStore::IN => match self.pending_input.take() {
+                        Some(v) => {
+                            self.interrupts.clear(InterruptSource::INPUT);
+                            v
+                        }
+                        None if self.input.is_empty_queue() => return Ok(Step::WaitingForInput),
+                        None => self.input.next()?,
+                    },
                     FromStore::UNKNOWN => {
-                        return Err(eyre!("0b111 is not a valid source for moving! Error occurred at instruction number {pc}"));
+                        self.retire(self.timing.mov);
+                        return match to {
+                            ToStore::REG0..=ToStore::REG5 => Ok(self.halt(self.registers[to as usize])),
+                            ToStore::UNKNOWN => Ok(self.halt(0)),
+                            _ => Err(eyre!("0b111 is not a valid source for moving! Moves from 0b111 halt the program, but the exit status must come from a register. Error occurred at instruction number {pc}")),
+                        };
                     }
                     _ => unsafe { unreachable_unchecked() },
                 };
+                self.retire(self.timing.mov);
+                if from == FromStore::IN {
+                    self.ticks += self.timing.input;
+                }
 
                 match to {
-                    ToStore::REG0..=ToStore::REG5 => registers[to as usize] = from_target,
+                    ToStore::REG0..=ToStore::REG5 => self.registers[to as usize] = from_target,
                     ToStore::OUT => {
-                        let _ = output.write(&[from_target])?;
+                        self.ticks += self.timing.output;
+                        let _ = self.output.write(&[from_target])?;
                     }
                     ToStore::UNKNOWN => {
                         return Err(eyre!(
@@ -238,15 +631,223 @@ pub fn interpret(program: &[u8], mut input: Input, mut output: Output) -> Result
                     }
                     _ => unsafe { unreachable_unchecked() },
                 };
-                increment_pc!(pc);
-                continue;
+                increment_pc!(self);
             }
             InstructionType::LOAD_LITERAL => {
-                registers[0] = body;
-                increment_pc!(pc);
-                continue;
+                self.retire(self.timing.load_literal);
+                self.registers[0] = body;
+                increment_pc!(self);
             }
             _ => unsafe { unreachable_unsafe!() },
         }
+        Ok(Step::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn mov(from: u8, to: u8) -> u8 {
+        MOVE_PREFIX << 6 | from << 3 | to
+    }
+
+    const fn arithmetic(operation: u8) -> u8 {
+        ARITHMETIC_PREFIX << 6 | operation
+    }
+
+    const fn conditional(source: u8, condition: u8) -> u8 {
+        CONDITIONAL_PREFIX << 6 | source << 3 | condition
+    }
+
+    /// Runs `program` until it halts, reading from `input`, and returns the registers.
+    fn run(program: &[u8], input: &[u8], extensions: u8) -> [u8; 6] {
+        let mut machine = Machine::new(program, Input::ARRAY(input), Output::ARRAY(&mut []), extensions);
+        machine.run().unwrap();
+        machine.registers()
+    }
+
+    /// The result of `operation` with reg1 and reg2 read from the input.
+    fn alu(operation: u8, reg1: u8, reg2: u8) -> u8 {
+        let program = [mov(FromStore::IN, ToStore::REG1), mov(FromStore::IN, ToStore::REG2), arithmetic(operation)];
+        run(&program, &[reg1, reg2], Extension::EXTENDED_ALU)[3]
+    }
+
+    #[test]
+    fn arithmetic_results() {
+        let cases = [
+            (Arithmetic::ADD, 48),
+            (Arithmetic::SUB, 42),
+            (Arithmetic::AND, 1),
+            (Arithmetic::NAND, 254),
+            (Arithmetic::OR, 47),
+            (Arithmetic::NOR, 208),
+            (Arithmetic::XOR, 46),
+            (Arithmetic::XNOR, 209),
+            (ExtendedArithmetic::SHL, 104),
+            (ExtendedArithmetic::SHR, 5),
+            (ExtendedArithmetic::ASR, 5),
+            (ExtendedArithmetic::ROL, 105),
+            (ExtendedArithmetic::ROR, 165),
+            (ExtendedArithmetic::MUL, 135),
+            (ExtendedArithmetic::MULH, 0),
+            (ExtendedArithmetic::INC, 46),
+            (ExtendedArithmetic::DEC, 44),
+        ];
+        for (operation, expected) in cases {
+            assert_eq!(alu(operation, 45, 3), expected, "{operation:#08b}");
+        }
+    }
+
+    #[test]
+    fn extended_arithmetic_edge_cases() {
+        assert_eq!(alu(ExtendedArithmetic::SHL, 0xff, 8), 0);
+        assert_eq!(alu(ExtendedArithmetic::SHR, 0xff, 200), 0);
+        assert_eq!(alu(ExtendedArithmetic::ASR, 0x80, 2), 0xe0);
+        assert_eq!(alu(ExtendedArithmetic::ASR, 0x80, 200), 0xff);
+        assert_eq!(alu(ExtendedArithmetic::ROL, 0x81, 9), 0x03);
+        assert_eq!(alu(ExtendedArithmetic::MUL, 200, 3), 88);
+        assert_eq!(alu(ExtendedArithmetic::MULH, 200, 3), 2);
+        assert_eq!(alu(ExtendedArithmetic::INC, 0xff, 0), 0);
+        assert_eq!(alu(ExtendedArithmetic::DEC, 0, 0), 0xff);
+    }
+
+    /// Whether the conditional jump with `source` and `condition` is taken, with reg1 and reg2 read from the input.
+    fn jumps(source: u8, condition: u8, reg1: u8, reg2: u8) -> bool {
+        let program = [
+            mov(FromStore::IN, ToStore::REG1),
+            mov(FromStore::IN, ToStore::REG2),
+            6,
+            conditional(source, condition),
+            Halt::SUCCESS,
+            Halt::SUCCESS,
+            mov(FromStore::REG0, ToStore::REG4),
+        ];
+        run(&program, &[reg1, reg2], Extension::REGISTER_CONDITIONALS)[4] == 6
+    }
+
+    #[test]
+    fn register_conditionals() {
+        assert!(jumps(ConditionalSource::REG1, Conditional::JLZ, 200, 0));
+        assert!(!jumps(ConditionalSource::REG1, Conditional::JGZ, 0, 5));
+        assert!(jumps(ConditionalSource::REG2, Conditional::JEZ, 5, 0));
+        assert!(jumps(ConditionalSource::REG2, Conditional::JGEZ, 5, 1));
+        assert!(!jumps(ConditionalSource::REG3, Conditional::JNZ, 5, 1));
+    }
+
+    #[test]
+    fn compare_conditionals() {
+        assert!(jumps(ConditionalSource::COMPARE, Conditional::JEZ, 7, 7));
+        assert!(!jumps(ConditionalSource::COMPARE, Conditional::JNZ, 7, 7));
+        assert!(jumps(ConditionalSource::COMPARE, Conditional::JLZ, 200, 3));
+        assert!(!jumps(ConditionalSource::COMPARE, Conditional::JGZ, 200, 3));
+        assert!(jumps(ConditionalSource::COMPARE_UNSIGNED, Conditional::JGZ, 200, 3));
+        assert!(!jumps(ConditionalSource::COMPARE_UNSIGNED, Conditional::JLZ, 200, 3));
+        assert!(jumps(ConditionalSource::COMPARE_UNSIGNED, Conditional::JLEZ, 3, 3));
+    }
+
+    #[test]
+    fn register_conditionals_need_the_extension() {
+        let program = [conditional(ConditionalSource::COMPARE, Conditional::JEZ)];
+        let mut machine = Machine::new(&program, Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::NONE);
+        assert!(machine.run().is_err());
+    }
+
+    #[test]
+    fn halt_exit_status() {
+        let program = [42, mov(FromStore::REG0, ToStore::REG4), Halt::with_status_from(ToStore::REG4), 7];
+        assert_eq!(interpret(&program, Input::ARRAY(&[]), Output::ARRAY(&mut [])).unwrap(), 42);
+        let program = [42, Halt::SUCCESS, Halt::with_status_from(ToStore::REG0)];
+        assert_eq!(interpret(&program, Input::ARRAY(&[]), Output::ARRAY(&mut [])).unwrap(), 0);
+    }
+
+    #[test]
+    fn running_off_the_end_exits_with_0() {
+        let program = [42, mov(FromStore::REG0, ToStore::REG3)];
+        let mut machine = Machine::new(&program, Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::NONE);
+        assert_eq!(machine.run().unwrap(), 0);
+        assert_eq!(machine.exit_status(), Some(0));
+        assert_eq!(machine.step().unwrap(), Step::Halted(0));
+    }
+
+    #[test]
+    fn halt_to_output_is_an_error() {
+        let program = [Halt::with_status_from(ToStore::OUT)];
+        assert!(interpret(&program, Input::ARRAY(&[]), Output::ARRAY(&mut [])).is_err());
+    }
+
+    /// Counts the timer interrupts taken while running 20 `nop`s, with the timer interrupt enabled by `mask`.
+    fn timer_interrupts(mask: u8) -> u8 {
+        let nop = conditional(ConditionalSource::REG3, Conditional::NOP);
+        let mut program = vec![27, arithmetic(Interrupt::IVEC), 10, arithmetic(Interrupt::ITIMER), mask, arithmetic(Interrupt::IMASK)];
+        program.extend([nop; 20]);
+        program.push(Halt::with_status_from(ToStore::REG5));
+        // The handler adds 1 to reg5 and checks that it was called for the timer.
+        program.extend([
+            mov(FromStore::REG5, ToStore::REG1),
+            1,
+            mov(FromStore::REG0, ToStore::REG2),
+            arithmetic(Arithmetic::ADD),
+            mov(FromStore::REG3, ToStore::REG5),
+            arithmetic(Interrupt::ICAUSE),
+            mov(FromStore::REG3, ToStore::REG4),
+            arithmetic(Interrupt::RETI),
+        ]);
+        let mut machine = Machine::new(&program, Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::INTERRUPTS);
+        let status = machine.run().unwrap();
+        if status != 0 {
+            assert_eq!(machine.registers()[4], InterruptSource::TIMER);
+        }
+        status
+    }
+
+    #[test]
+    fn timer_interrupts_return_to_the_program() {
+        // The timer keeps counting in the 8 instructions of the handler, so after the first interrupt every 10 instructions
+        // run 2 instructions of the program.
+        assert_eq!(timer_interrupts(InterruptSource::TIMER), 7);
+        assert_eq!(timer_interrupts(0), 0);
+        assert_eq!(timer_interrupts(InterruptSource::INPUT), 0);
+    }
+
+    #[test]
+    fn reti_outside_of_an_interrupt_handler() {
+        let program = [arithmetic(Interrupt::RETI)];
+        let mut machine = Machine::new(&program, Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::INTERRUPTS);
+        assert!(machine.run().is_err());
+    }
+
+    #[test]
+    fn ticks_follow_the_timing_model() {
+        // The jump is taken once, and the input and output are used once each.
+        let program = [
+            mov(FromStore::IN, ToStore::REG3),
+            mov(FromStore::REG3, ToStore::OUT),
+            4,
+            conditional(ConditionalSource::REG3, Conditional::JNZ),
+            Halt::SUCCESS,
+        ];
+        let mut output = [0];
+        let mut machine = Machine::new(&program, Input::ARRAY(&[1]), Output::ARRAY(&mut output), Extension::NONE);
+        machine.set_timing_model("literal=2,conditional=3,move=5,taken-jump=7,input=11,output=13".parse().unwrap());
+        machine.run().unwrap();
+        assert_eq!(machine.instructions_executed(), 5);
+        assert_eq!(machine.ticks(), 5 + 11 + 5 + 13 + 2 + 3 + 7 + 5);
+    }
+
+    #[test]
+    fn owned_programs() {
+        let output: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let program = vec![42, mov(FromStore::REG0, ToStore::OUT)];
+        let mut machine: Machine<'static> = Machine::new_owned(program, Input::ARRAY(&[]), Output::QUEUE(output.clone()), Extension::NONE);
+        assert_eq!(machine.run().unwrap(), 0);
+        assert_eq!(*output.borrow(), [42]);
+    }
+
+    #[test]
+    fn extended_arithmetic_needs_the_extension() {
+        let program = [arithmetic(ExtendedArithmetic::SHL)];
+        let mut machine = Machine::new(&program, Input::ARRAY(&[]), Output::ARRAY(&mut []), Extension::NONE);
+        assert!(machine.run().is_err());
     }
 }
@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Error, Result};
+
+/// How many ticks each kind of instruction takes, used to score programs the way Turing Complete does.
+///
+/// The default model matches the OVERTURE architecture, where every instruction takes one tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingModel {
+    pub load_literal: u64,
+    pub conditional: u64,
+    pub mov: u64,
+    pub arithmetic: u64,
+    /// Extra ticks on top of `conditional` when the jump is taken.
+    pub taken_jump: u64,
+    /// Extra ticks on top of `mov` when reading from the input.
+    pub input: u64,
+    /// Extra ticks on top of `mov` when writing to the output.
+    pub output: u64,
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        Self {
+            load_literal: 1,
+            conditional: 1,
+            mov: 1,
+            arithmetic: 1,
+            taken_jump: 0,
+            input: 0,
+            output: 0,
+        }
+    }
+}
+
+impl FromStr for TimingModel {
+    type Err = Error;
+
+    /// Parses a comma separated list of costs that override the default model, EG "taken-jump=1,input=2".
+    ///
+    /// The costs that can be set are literal, conditional, move, arithmetic, taken-jump, input and output.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut model = Self::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = match entry.split_once('=') {
+                Some(v) => v,
+                None => return Err(eyre!("Bad timing entry \"{entry}\". Entries should look like NAME=TICKS.")),
+            };
+            let value = match value.trim().parse() {
+                Ok(v) => v,
+                Err(e) => return Err(eyre!("Bad number of ticks in timing entry \"{entry}\": {e}")),
+            };
+            let cost = match name.trim() {
+                "literal" => &mut model.load_literal,
+                "conditional" => &mut model.conditional,
+                "move" => &mut model.mov,
+                "arithmetic" => &mut model.arithmetic,
+                "taken-jump" => &mut model.taken_jump,
+                "input" => &mut model.input,
+                "output" => &mut model.output,
+                other => return Err(eyre!("Unknown instruction kind \"{other}\" in timing entry \"{entry}\". Known kinds are literal, conditional, move, arithmetic, taken-jump, input and output.")),
+            };
+            *cost = value;
+        }
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        let model: TimingModel = "taken-jump=1, input=2,output = 3".parse().unwrap();
+        assert_eq!(
+            model,
+            TimingModel {
+                taken_jump: 1,
+                input: 2,
+                output: 3,
+                ..TimingModel::default()
+            }
+        );
+        let model: TimingModel = "literal=4,conditional=5,move=6,arithmetic=7".parse().unwrap();
+        assert_eq!((model.load_literal, model.conditional, model.mov, model.arithmetic), (4, 5, 6, 7));
+    }
+
+    #[test]
+    fn empty_is_the_default() {
+        assert_eq!("".parse::<TimingModel>().unwrap(), TimingModel::default());
+        assert_eq!(",".parse::<TimingModel>().unwrap(), TimingModel::default());
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        assert!("input".parse::<TimingModel>().is_err());
+        assert!("input=lots".parse::<TimingModel>().is_err());
+        assert!("input=-1".parse::<TimingModel>().is_err());
+        assert!("jump=1".parse::<TimingModel>().is_err());
+    }
+}
@@ -0,0 +1,24 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Generates the header from the functions in src/lib.rs into OUT_DIR, so every build checks that it can be generated.
+///
+/// The header in include/myvm.h is committed, and is only overwritten when MYVM_WRITE_HEADER is set, so building does
+/// not change the source tree.
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    let bindings = cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Failed at generating the C header.");
+    bindings.write_to_file(out_dir.join("myvm.h"));
+    if env::var_os("MYVM_WRITE_HEADER").is_some() {
+        bindings.write_to_file(crate_dir.join("include").join("myvm.h"));
+    }
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=MYVM_WRITE_HEADER");
+}
@@ -0,0 +1,45 @@
+// Assembles a program that uppercases its input and runs it through the C API.
+// Build the library with `cargo build` and then:
+//     cc examples/echo.c -Iinclude -Ltarget/debug -lmyvm -o echo && LD_LIBRARY_PATH=target/debug ./echo
+#include <stdio.h>
+#include <string.h>
+
+#include "myvm.h"
+
+static const char *source =
+    "program:\n"
+    "label loop:\n"
+    "mov input 1\n"
+    "32\n"
+    "mov 0 2\n"
+    "sub\n"
+    "mov 3 output\n"
+    "loop\n"
+    "j\n";
+
+int main(void) {
+    char *error = NULL;
+    MyvmProgram *program = myvm_assemble(source, &error);
+    if (program == NULL) {
+        fprintf(stderr, "%s\n", error);
+        myvm_string_free(error);
+        return 1;
+    }
+    size_t length;
+    const uint8_t *bytes = myvm_program_bytes(program, &length);
+    MyvmMachine *machine = myvm_machine_new(bytes, length, MYVM_EXTENSION_NONE);
+    myvm_program_free(program);
+
+    const char *input = "hello";
+    myvm_machine_push_input(machine, (const uint8_t *)input, strlen(input));
+    MyvmStatus status = myvm_machine_run(machine, 1000);
+
+    uint8_t output[64];
+    size_t taken = myvm_machine_take_output(machine, output, sizeof(output));
+    uint8_t registers[6];
+    myvm_machine_registers(machine, registers);
+    printf("%.*s\n", (int)taken, (const char *)output);
+    printf("status %d, pc %d, reg3 %d\n", status, myvm_machine_pc(machine), registers[3]);
+    myvm_machine_free(machine);
+    return status == MYVM_STATUS_WAITING_FOR_INPUT ? 0 : 1;
+}
@@ -0,0 +1,149 @@
+/* Generated by cbindgen from src/c_interface/src/lib.rs. Do not edit by hand. */
+
+#ifndef MYVM_H
+#define MYVM_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Flags for the instruction set extensions, which can be combined and passed to `myvm_machine_new`.
+#define MYVM_EXTENSION_NONE 0
+
+#define MYVM_EXTENSION_EXTENDED_ALU 1
+
+#define MYVM_EXTENSION_REGISTER_CONDITIONALS 2
+
+#define MYVM_EXTENSION_INTERRUPTS 4
+
+// What a machine did when it was stepped.
+typedef enum MyvmStatus {
+  // The machine executed an instruction and can keep going.
+  MYVM_STATUS_RUNNING = 0,
+  // The program has halted. Its exit status can be read with `myvm_machine_exit_status`.
+  MYVM_STATUS_HALTED = 1,
+  // The program tried to read input, but the input queue is empty. Push more input and step again.
+  MYVM_STATUS_WAITING_FOR_INPUT = 2,
+  // The program did something invalid. The error can be read with `myvm_machine_last_error`.
+  MYVM_STATUS_ERROR = 3,
+} MyvmStatus;
+
+// A machine running a program. Its input and output are queues that are filled and drained through this API.
+typedef struct MyvmMachine MyvmMachine;
+
+// An assembled program.
+typedef struct MyvmProgram MyvmProgram;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Assembles `source`, a NUL terminated UTF-8 string.
+//
+// Returns NULL if the program could not be assembled. If `error` is not NULL, it is then set to a message that must be freed with `myvm_string_free`.
+//
+// # Safety
+// `source` must be a valid NUL terminated string, and `error` must be NULL or point to writable memory.
+struct MyvmProgram *myvm_assemble(const char *source,
+                                  char **error);
+
+// Returns the bytecode of the program and sets `length` to its length. The bytes live as long as the program.
+//
+// # Safety
+// `program` must come from `myvm_assemble` and `length` must point to writable memory.
+const uint8_t *myvm_program_bytes(const struct MyvmProgram *program,
+                                  size_t *length);
+
+// Returns the input from the input section of the program and sets `length` to its length. The bytes live as long as the program.
+//
+// # Safety
+// `program` must come from `myvm_assemble` and `length` must point to writable memory.
+const uint8_t *myvm_program_input(const struct MyvmProgram *program,
+                                  size_t *length);
+
+// # Safety
+// `program` must be NULL or come from `myvm_assemble`, and must not be used afterwards.
+void myvm_program_free(struct MyvmProgram *program);
+
+// Creates a machine that runs a copy of the `length` bytes at `program`, with the instruction set extensions in `extensions`.
+//
+// Returns NULL if the program is longer than 255 bytes.
+//
+// # Safety
+// `program` must point to `length` readable bytes.
+struct MyvmMachine *myvm_machine_new(const uint8_t *program,
+                                     size_t length,
+                                     uint8_t extensions);
+
+// Executes one instruction.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+enum MyvmStatus myvm_machine_step(struct MyvmMachine *machine);
+
+// Steps the machine until it stops running or has executed `max_steps` instructions. Returns the status of the last step.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+enum MyvmStatus myvm_machine_run(struct MyvmMachine *machine,
+                                 uint64_t max_steps);
+
+// Copies the six registers into `registers`, which must have room for 6 bytes.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new` and `registers` must point to 6 writable bytes.
+void myvm_machine_registers(const struct MyvmMachine *machine, uint8_t *registers);
+
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+uint8_t myvm_machine_pc(const struct MyvmMachine *machine);
+
+// Returns the exit status of the program, or 0 if it has not halted.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+uint8_t myvm_machine_exit_status(const struct MyvmMachine *machine);
+
+// Returns the error from the last step that failed, or NULL. The string lives until the next step that fails or until the machine is freed.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+const char *myvm_machine_last_error(const struct MyvmMachine *machine);
+
+// Adds `length` bytes to the end of the input of the machine.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new` and `bytes` must point to `length` readable bytes.
+void myvm_machine_push_input(struct MyvmMachine *machine, const uint8_t *bytes, size_t length);
+
+// Returns how many bytes of output are waiting to be taken.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new`.
+size_t myvm_machine_output_length(const struct MyvmMachine *machine);
+
+// Moves up to `capacity` bytes of output into `buffer`. Returns how many bytes were moved, the rest stay queued.
+//
+// # Safety
+// `machine` must come from `myvm_machine_new` and `buffer` must point to `capacity` writable bytes.
+size_t myvm_machine_take_output(struct MyvmMachine *machine,
+                                uint8_t *buffer,
+                                size_t capacity);
+
+// # Safety
+// `machine` must be NULL or come from `myvm_machine_new`, and must not be used afterwards.
+void myvm_machine_free(struct MyvmMachine *machine);
+
+// Frees a string returned by this library.
+//
+// # Safety
+// `string` must be NULL or a string this library said to free with this function.
+void myvm_string_free(char *string);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* MYVM_H */
@@ -0,0 +1,449 @@
+//! A C API for assembling and running programs, so tools written in C and C++ can drive the VM without the CLI.
+//!
+//! The header for this library is `include/myvm.h`. Build the crate with `MYVM_WRITE_HEADER=1` to regenerate it.
+//! Every object returned by this library must be freed with the matching `myvm_*_free` function.
+//! A panic never unwinds into C: functions that can report errors report it as one, the others return their fallback value.
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+use assembler::parser::{parse, SuccessfulParse};
+use bytecode_interpreter::run::{Extension, Input, Machine, Output, Queue, Step, MAX_PROGRAM_LENGTH};
+
+/// Flags for the instruction set extensions, which can be combined and passed to `myvm_machine_new`.
+pub const MYVM_EXTENSION_NONE: u8 = 0;
+pub const MYVM_EXTENSION_EXTENDED_ALU: u8 = 1;
+pub const MYVM_EXTENSION_REGISTER_CONDITIONALS: u8 = 2;
+pub const MYVM_EXTENSION_INTERRUPTS: u8 = 4;
+
+// The header needs plain numbers, so make sure they stay in sync with the interpreter.
+const _: () = assert!(
+    MYVM_EXTENSION_NONE == Extension::NONE
+        && MYVM_EXTENSION_EXTENDED_ALU == Extension::EXTENDED_ALU
+        && MYVM_EXTENSION_REGISTER_CONDITIONALS == Extension::REGISTER_CONDITIONALS
+        && MYVM_EXTENSION_INTERRUPTS == Extension::INTERRUPTS
+);
+
+/// An assembled program.
+pub struct MyvmProgram {
+    parsed: SuccessfulParse,
+}
+
+/// A machine running a program. Its input and output are queues that are filled and drained through this API.
+pub struct MyvmMachine {
+    machine: Machine<'static>,
+    input: Queue,
+    output: Queue,
+    last_error: Option<CString>,
+}
+
+/// What a machine did when it was stepped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MyvmStatus {
+    /// The machine executed an instruction and can keep going.
+    Running = 0,
+    /// The program has halted. Its exit status can be read with `myvm_machine_exit_status`.
+    Halted = 1,
+    /// The program tried to read input, but the input queue is empty. Push more input and step again.
+    WaitingForInput = 2,
+    /// The program did something invalid. The error can be read with `myvm_machine_last_error`.
+    Error = 3,
+}
+
+/// Assembles `source`, a NUL terminated UTF-8 string.
+///
+/// Returns NULL if the program could not be assembled. If `error` is not NULL, it is then set to a message that must be freed with `myvm_string_free`.
+///
+/// # Safety
+/// `source` must be a valid NUL terminated string, and `error` must be NULL or point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_assemble(source: *const c_char, error: *mut *mut c_char) -> *mut MyvmProgram {
+    if !error.is_null() {
+        *error = ptr::null_mut();
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match CStr::from_ptr(source).to_str() {
+        Ok(source) => parse(source).map_err(|e| e.to_string()),
+        Err(e) => Err(format!("The source is not valid UTF-8: {e}")),
+    }))
+    .unwrap_or_else(|e| Err(panic_message(e)));
+    match result {
+        Ok(parsed) => Box::into_raw(Box::new(MyvmProgram { parsed })),
+        Err(e) => {
+            if !error.is_null() {
+                *error = to_c_string(e).into_raw();
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Returns the bytecode of the program and sets `length` to its length. The bytes live as long as the program.
+///
+/// # Safety
+/// `program` must come from `myvm_assemble` and `length` must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_program_bytes(program: *const MyvmProgram, length: *mut usize) -> *const u8 {
+    catch(ptr::null(), || {
+        let bytes = (*program).parsed.program();
+        *length = bytes.len();
+        bytes.as_ptr()
+    })
+}
+
+/// Returns the input from the input section of the program and sets `length` to its length. The bytes live as long as the program.
+///
+/// # Safety
+/// `program` must come from `myvm_assemble` and `length` must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_program_input(program: *const MyvmProgram, length: *mut usize) -> *const u8 {
+    catch(ptr::null(), || {
+        let bytes = (*program).parsed.input();
+        *length = bytes.len();
+        bytes.as_ptr()
+    })
+}
+
+/// # Safety
+/// `program` must be NULL or come from `myvm_assemble`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_program_free(program: *mut MyvmProgram) {
+    if !program.is_null() {
+        catch((), || drop(Box::from_raw(program)));
+    }
+}
+
+/// Creates a machine that runs a copy of the `length` bytes at `program`, with the instruction set extensions in `extensions`.
+///
+/// Returns NULL if the program is longer than 255 bytes.
+///
+/// # Safety
+/// `program` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_new(program: *const u8, length: usize, extensions: u8) -> *mut MyvmMachine {
+    if length > MAX_PROGRAM_LENGTH {
+        return ptr::null_mut();
+    }
+    catch(ptr::null_mut(), || {
+        let program = slice::from_raw_parts(program, length).to_vec();
+        let input: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let output: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let machine = Machine::new_owned(program, Input::QUEUE(input.clone()), Output::QUEUE(output.clone()), extensions);
+        Box::into_raw(Box::new(MyvmMachine {
+            machine,
+            input,
+            output,
+            last_error: None,
+        }))
+    })
+}
+
+/// Executes one instruction.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_step(machine: *mut MyvmMachine) -> MyvmStatus {
+    let machine = &mut *machine;
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| machine.machine.step())) {
+        Ok(Ok(Step::Running)) => return MyvmStatus::Running,
+        Ok(Ok(Step::Halted(_))) => return MyvmStatus::Halted,
+        Ok(Ok(Step::WaitingForInput)) => return MyvmStatus::WaitingForInput,
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => panic_message(e),
+    };
+    machine.last_error = Some(to_c_string(error));
+    MyvmStatus::Error
+}
+
+/// Steps the machine until it stops running or has executed `max_steps` instructions. Returns the status of the last step.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_run(machine: *mut MyvmMachine, max_steps: u64) -> MyvmStatus {
+    let mut status = MyvmStatus::Running;
+    for _ in 0..max_steps {
+        status = myvm_machine_step(machine);
+        if status != MyvmStatus::Running {
+            break;
+        }
+    }
+    status
+}
+
+/// Copies the six registers into `registers`, which must have room for 6 bytes.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new` and `registers` must point to 6 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_registers(machine: *const MyvmMachine, registers: *mut u8) {
+    catch((), || {
+        let source = (*machine).machine.registers();
+        ptr::copy_nonoverlapping(source.as_ptr(), registers, source.len());
+    })
+}
+
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_pc(machine: *const MyvmMachine) -> u8 {
+    catch(0, || (*machine).machine.pc())
+}
+
+/// Returns the exit status of the program, or 0 if it has not halted.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_exit_status(machine: *const MyvmMachine) -> u8 {
+    catch(0, || (*machine).machine.exit_status().unwrap_or(0))
+}
+
+/// Returns the error from the last step that failed, or NULL. The string lives until the next step that fails or until the machine is freed.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_last_error(machine: *const MyvmMachine) -> *const c_char {
+    catch(ptr::null(), || match &(*machine).last_error {
+        Some(e) => e.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Adds `length` bytes to the end of the input of the machine.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new` and `bytes` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_push_input(machine: *mut MyvmMachine, bytes: *const u8, length: usize) {
+    catch((), || (*machine).input.borrow_mut().extend(slice::from_raw_parts(bytes, length)))
+}
+
+/// Returns how many bytes of output are waiting to be taken.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_output_length(machine: *const MyvmMachine) -> usize {
+    catch(0, || (*machine).output.borrow().len())
+}
+
+/// Moves up to `capacity` bytes of output into `buffer`. Returns how many bytes were moved, the rest stay queued.
+///
+/// # Safety
+/// `machine` must come from `myvm_machine_new` and `buffer` must point to `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_take_output(machine: *mut MyvmMachine, buffer: *mut u8, capacity: usize) -> usize {
+    catch(0, || {
+        let mut output = (*machine).output.borrow_mut();
+        let taken = output.len().min(capacity);
+        for (i, byte) in output.drain(..taken).enumerate() {
+            *buffer.add(i) = byte;
+        }
+        taken
+    })
+}
+
+/// # Safety
+/// `machine` must be NULL or come from `myvm_machine_new`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_machine_free(machine: *mut MyvmMachine) {
+    if !machine.is_null() {
+        catch((), || drop(Box::from_raw(machine)));
+    }
+}
+
+/// Frees a string returned by this library.
+///
+/// # Safety
+/// `string` must be NULL or a string this library said to free with this function.
+#[no_mangle]
+pub unsafe extern "C" fn myvm_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        catch((), || drop(CString::from_raw(string)));
+    }
+}
+
+/// Runs `f`, returning `fallback` instead if it panics.
+fn catch<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().copied().unwrap_or("unknown error").to_string(),
+    };
+    format!("Internal error: {message}")
+}
+
+fn to_c_string(message: String) -> CString {
+    // Error messages never contain NUL bytes, but if one does it is better to cut the message short than to fail.
+    let message = match message.find('\0') {
+        Some(v) => message[..v].to_string(),
+        None => message,
+    };
+    CString::new(message).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(source: &str) -> Result<*mut MyvmProgram, String> {
+        let source = CString::new(source).unwrap();
+        let mut error = ptr::null_mut();
+        unsafe {
+            let program = myvm_assemble(source.as_ptr(), &mut error);
+            if program.is_null() {
+                assert!(!error.is_null());
+                let message = CStr::from_ptr(error).to_str().unwrap().to_string();
+                myvm_string_free(error);
+                Err(message)
+            } else {
+                assert!(error.is_null());
+                Ok(program)
+            }
+        }
+    }
+
+    fn machine_for(source: &str) -> *mut MyvmMachine {
+        let program = assemble(source).unwrap();
+        unsafe {
+            let mut length = 0;
+            let bytes = myvm_program_bytes(program, &mut length);
+            let machine = myvm_machine_new(bytes, length, MYVM_EXTENSION_NONE);
+            myvm_program_free(program);
+            assert!(!machine.is_null());
+            machine
+        }
+    }
+
+    fn take_output(machine: *mut MyvmMachine) -> Vec<u8> {
+        unsafe {
+            let mut output = vec![0; myvm_machine_output_length(machine)];
+            let taken = myvm_machine_take_output(machine, output.as_mut_ptr(), output.len());
+            output.truncate(taken);
+            output
+        }
+    }
+
+    #[test]
+    fn assembles_programs() {
+        let program = assemble("input:\n1, 2\nprogram:\n7\nmov 0 out\n").unwrap();
+        unsafe {
+            let mut length = 0;
+            let bytes = myvm_program_bytes(program, &mut length);
+            assert_eq!(slice::from_raw_parts(bytes, length), [7, 0b10_000_110]);
+            let input = myvm_program_input(program, &mut length);
+            assert_eq!(slice::from_raw_parts(input, length), [1, 2]);
+            myvm_program_free(program);
+        }
+    }
+
+    #[test]
+    fn reports_assembly_errors() {
+        let error = assemble("program:\nmov 0 7\n").unwrap_err();
+        assert!(!error.is_empty());
+        unsafe {
+            // The error out-parameter is optional.
+            let source = CString::new("not a program").unwrap();
+            assert!(myvm_assemble(source.as_ptr(), ptr::null_mut()).is_null());
+            let invalid = [0xff, 0];
+            let mut error = ptr::null_mut();
+            assert!(myvm_assemble(invalid.as_ptr().cast(), &mut error).is_null());
+            assert!(CStr::from_ptr(error).to_str().unwrap().contains("UTF-8"));
+            myvm_string_free(error);
+        }
+    }
+
+    #[test]
+    fn runs_programs() {
+        let machine = machine_for("program:\nmov in 1\nmov in 2\nadd\nmov 3 out\nhalt reg3\n");
+        unsafe {
+            myvm_machine_push_input(machine, [3].as_ptr(), 1);
+            assert_eq!(myvm_machine_run(machine, 100), MyvmStatus::WaitingForInput);
+            assert_eq!(myvm_machine_pc(machine), 1);
+            myvm_machine_push_input(machine, [4].as_ptr(), 1);
+            assert_eq!(myvm_machine_step(machine), MyvmStatus::Running);
+            assert_eq!(myvm_machine_run(machine, 100), MyvmStatus::Halted);
+            assert_eq!(myvm_machine_exit_status(machine), 7);
+            let mut registers = [0; 6];
+            myvm_machine_registers(machine, registers.as_mut_ptr());
+            assert_eq!(registers[1..4], [3, 4, 7]);
+            assert_eq!(take_output(machine), [7]);
+            assert_eq!(myvm_machine_output_length(machine), 0);
+            assert!(myvm_machine_last_error(machine).is_null());
+            myvm_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn output_is_taken_in_pieces() {
+        let machine = machine_for("program:\n1\nmov 0 out\n2\nmov 0 out\n3\nmov 0 out\nhalt\n");
+        unsafe {
+            assert_eq!(myvm_machine_run(machine, 100), MyvmStatus::Halted);
+            let mut buffer = [0; 2];
+            assert_eq!(myvm_machine_take_output(machine, buffer.as_mut_ptr(), 2), 2);
+            assert_eq!(buffer, [1, 2]);
+            assert_eq!(take_output(machine), [3]);
+            myvm_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn run_stops_after_max_steps() {
+        let machine = machine_for("program:\nlabel loop:\nloop\nj\n");
+        unsafe {
+            assert_eq!(myvm_machine_run(machine, 3), MyvmStatus::Running);
+            assert_eq!(myvm_machine_exit_status(machine), 0);
+            myvm_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn reports_machine_errors() {
+        // An extended ALU instruction on a machine without the extension.
+        let program = [0b11_001_000];
+        unsafe {
+            let machine = myvm_machine_new(program.as_ptr(), program.len(), MYVM_EXTENSION_NONE);
+            assert_eq!(myvm_machine_run(machine, 100), MyvmStatus::Error);
+            assert!(!CStr::from_ptr(myvm_machine_last_error(machine)).to_bytes().is_empty());
+            myvm_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn long_programs_are_rejected() {
+        let program = [0; MAX_PROGRAM_LENGTH + 1];
+        unsafe {
+            assert!(myvm_machine_new(program.as_ptr(), program.len(), MYVM_EXTENSION_NONE).is_null());
+        }
+    }
+
+    #[test]
+    fn freeing_null_does_nothing() {
+        unsafe {
+            myvm_program_free(ptr::null_mut());
+            myvm_machine_free(ptr::null_mut());
+            myvm_string_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn panics_do_not_escape() {
+        assert_eq!(catch(1, || panic!("oops")), 1);
+        let message = panic::catch_unwind(|| panic!("{}", 2)).unwrap_err();
+        assert_eq!(panic_message(message), "Internal error: 2");
+        let message = panic::catch_unwind(|| panic!("oops")).unwrap_err();
+        assert_eq!(panic_message(message), "Internal error: oops");
+    }
+}
@@ -0,0 +1,418 @@
+//! A language server for myvm assembly, so editors can show errors as you type and navigate programs.
+//!
+//! It speaks the language server protocol over stdin and stdout. Every request works on the whole document, which is
+//! re-read from the last version the editor sent, since programs are at most a few hundred lines long.
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Range;
+
+use assembler::diagnostics::{diagnose_with, Severity};
+use assembler::include::FileResolver;
+use assembler::parser::{parse_with, ParseOptions};
+use assembler::symbols::{SymbolKind, SymbolTable};
+use lsp_server::{Connection, ErrorCode, ExtractError, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse, Diagnostic, DiagnosticSeverity,
+    DocumentSymbol, DocumentSymbolResponse, GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability,
+    Location, MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Mnemonics and what they do, for completion.
+const MNEMONICS: &[(&str, &str)] = &[
+    ("nop", "Does nothing"),
+    ("j", "Jumps to the address in reg0"),
+    ("jez", "Jumps to the address in reg0 if reg3 is zero"),
+    ("jnz", "Jumps to the address in reg0 if reg3 is not zero"),
+    ("jgz", "Jumps to the address in reg0 if reg3 is greater than zero"),
+    ("jgez", "Jumps to the address in reg0 if reg3 is greater than or equal to zero"),
+    ("jlz", "Jumps to the address in reg0 if reg3 is less than zero"),
+    ("jlez", "Jumps to the address in reg0 if reg3 is less than or equal to zero"),
+    ("halt", "Halts the program, with the exit status in the register given"),
+    ("mov", "Copies a value from one register to another"),
+    ("ldi", "Loads any byte into a register, like `ldi 200 -> reg4`. Overwrites reg0, and reg1 to reg3 for values above 63"),
+    ("print", "Writes the UTF-8 bytes of a string, like `print \"Hello!\\n\"`. Overwrites reg0 to reg3"),
+    ("add", "reg3 = reg1 + reg2"),
+    ("sub", "reg3 = reg1 - reg2"),
+    ("and", "reg3 = reg1 & reg2"),
+    ("nand", "reg3 = !(reg1 & reg2)"),
+    ("or", "reg3 = reg1 | reg2"),
+    ("nor", "reg3 = !(reg1 | reg2)"),
+    ("xor", "reg3 = reg1 ^ reg2"),
+    ("xnor", "reg3 = !(reg1 ^ reg2)"),
+    ("shl", "Extended ALU: reg3 = reg1 << reg2"),
+    ("shr", "Extended ALU: reg3 = reg1 >> reg2"),
+    ("asr", "Extended ALU: reg3 = reg1 >> reg2, keeping the sign"),
+    ("rol", "Extended ALU: reg3 = reg1 rotated left by reg2"),
+    ("ror", "Extended ALU: reg3 = reg1 rotated right by reg2"),
+    ("mul", "Extended ALU: reg3 = the low byte of reg1 * reg2"),
+    ("mulh", "Extended ALU: reg3 = the high byte of reg1 * reg2"),
+    ("inc", "Extended ALU: reg3 = reg1 + 1"),
+    ("dec", "Extended ALU: reg3 = reg1 - 1"),
+    ("imask", "Interrupts: sets the interrupt mask to reg0"),
+    ("ivec", "Interrupts: sets the interrupt handler address to reg0"),
+    ("itimer", "Interrupts: sets the timer interrupt period to reg0"),
+    ("reti", "Interrupts: returns from an interrupt handler"),
+    ("icause", "Interrupts: reg3 = what caused the interrupt"),
+    ("label", "Defines a label"),
+    ("macro", "Defines a macro"),
+];
+
+const REGISTERS: &[&str] = &["reg0", "reg1", "reg2", "reg3", "reg4", "reg5", "input", "output"];
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    Server::default().run(connection)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+/// The documents the editor has open, by URI.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Url, String>,
+}
+
+impl Server {
+    /// Handles messages until the editor asks the server to shut down. The connection has to be dropped before the IO threads can finish.
+    fn run(&mut self, connection: Connection) -> Result<()> {
+        for message in &connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    let response = self.respond(request);
+                    connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => {
+                    if let Some(diagnostics) = self.handle_notification(notification)? {
+                        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), diagnostics);
+                        connection.sender.send(Message::Notification(notification))?;
+                    }
+                }
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps track of the open documents. Returns the new diagnostics for a document if it changed.
+    fn handle_notification(&mut self, notification: Notification) -> Result<Option<PublishDiagnosticsParams>> {
+        let notification = match cast_notification::<DidOpenTextDocument>(notification) {
+            Ok(params) => {
+                let uri = params.text_document.uri;
+                self.documents.insert(uri.clone(), params.text_document.text);
+                return Ok(Some(self.diagnostics(uri)));
+            }
+            Err(v) => v,
+        };
+        let notification = match cast_notification::<DidChangeTextDocument>(notification) {
+            Ok(params) => {
+                let uri = params.text_document.uri;
+                // The server asks for full syncing, so the last change is the whole document.
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                return Ok(Some(self.diagnostics(uri)));
+            }
+            Err(v) => v,
+        };
+        if let Ok(params) = cast_notification::<DidCloseTextDocument>(notification) {
+            let uri = params.text_document.uri;
+            self.documents.remove(&uri);
+            return Ok(Some(PublishDiagnosticsParams::new(uri, Vec::new(), None)));
+        }
+        Ok(None)
+    }
+
+    /// Answers a request, with an error response if it could not be handled, so a bad request does not stop the server.
+    fn respond(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        self.handle_request(request)
+            .unwrap_or_else(|e| Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()))
+    }
+
+    fn handle_request(&self, request: Request) -> Result<Response> {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                let params = cast_request::<GotoDefinition>(request)?.text_document_position_params;
+                let uri = params.text_document.uri;
+                serde_json::to_value(self.definition(&uri, params.position).map(GotoDefinitionResponse::Scalar))?
+            }
+            References::METHOD => {
+                let params = cast_request::<References>(request)?;
+                let position = params.text_document_position;
+                let include_declaration = params.context.include_declaration;
+                serde_json::to_value(self.references(&position.text_document.uri, position.position, include_declaration))?
+            }
+            HoverRequest::METHOD => {
+                let params = cast_request::<HoverRequest>(request)?.text_document_position_params;
+                serde_json::to_value(self.hover(&params.text_document.uri, params.position))?
+            }
+            Completion::METHOD => {
+                let params = cast_request::<Completion>(request)?.text_document_position;
+                serde_json::to_value(CompletionResponse::Array(self.completion(&params.text_document.uri)))?
+            }
+            DocumentSymbolRequest::METHOD => {
+                let params = cast_request::<DocumentSymbolRequest>(request)?;
+                serde_json::to_value(self.document_symbols(&params.text_document.uri).map(DocumentSymbolResponse::Nested))?
+            }
+            _ => serde_json::Value::Null,
+        };
+        Ok(Response::new_ok(id, result))
+    }
+
+    fn diagnostics(&self, uri: Url) -> PublishDiagnosticsParams {
+        let source = self.documents.get(&uri).map_or("", String::as_str);
+        let diagnostics = diagnose_with(source, &parse_options(&uri))
+            .into_iter()
+            .map(|d| Diagnostic {
+                range: to_range(source, &d.span),
+                severity: Some(match d.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                source: Some("myvm".to_string()),
+                message: d.full_message(),
+                ..Diagnostic::default()
+            })
+            .collect();
+        PublishDiagnosticsParams::new(uri, diagnostics, None)
+    }
+
+    /// Reads the symbols of a document and finds the symbol at `position`.
+    fn analyze(&self, uri: &Url, position: Position) -> Option<(&str, SymbolTable, usize)> {
+        let source = self.documents.get(uri)?;
+        let table = SymbolTable::analyze(source).ok()?;
+        let index = table.symbol_at(to_offset(source, position))?;
+        Some((source, table, index))
+    }
+
+    fn definition(&self, uri: &Url, position: Position) -> Option<Location> {
+        let (source, table, index) = self.analyze(uri, position)?;
+        Some(Location::new(uri.clone(), to_range(source, &table.symbols[index].name_span)))
+    }
+
+    fn references(&self, uri: &Url, position: Position, include_declaration: bool) -> Option<Vec<Location>> {
+        let (source, table, index) = self.analyze(uri, position)?;
+        let declaration = include_declaration.then_some(&table.symbols[index].name_span);
+        let locations = declaration
+            .into_iter()
+            .chain(table.references_to(index))
+            .map(|span| Location::new(uri.clone(), to_range(source, span)))
+            .collect();
+        Some(locations)
+    }
+
+    /// Shows the value of constants and labels, and the body of macros. Hovering over a macro call shows what it expands to.
+    fn hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        let (source, table, index) = self.analyze(uri, position)?;
+        let symbol = &table.symbols[index];
+        let text = match symbol.kind {
+            SymbolKind::Constant => format!("```myvm\n{} = {}\n```", symbol.name, symbol.value),
+            SymbolKind::Label => {
+                let parsed = parse_with(source, &parse_options(uri)).ok();
+                let address = parsed
+                    .as_ref()
+                    .and_then(|p| p.labels().iter().find(|(name, _)| *name == symbol.name))
+                    .map(|(_, address)| format!("\n\nAddress: {address}"));
+                format!("```myvm\nlabel {}:\n```{}", symbol.name, address.unwrap_or_default())
+            }
+            SymbolKind::Macro => {
+                let offset = to_offset(source, position);
+                let signature = format!("macro {}({}):", symbol.name, symbol.parameters.join(", "));
+                match table.call_at(offset).and_then(|c| table.expand(c)) {
+                    Some(expansion) => format!("```myvm\n{signature}\n```\nExpands to:\n```myvm\n{}\n```", expansion.trim()),
+                    None => format!("```myvm\n{signature}\n{}\nend_macro:\n```", symbol.value.trim()),
+                }
+            }
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        })
+    }
+
+    fn completion(&self, uri: &Url) -> Vec<CompletionItem> {
+        let mnemonics = MNEMONICS.iter().map(|(name, detail)| CompletionItem {
+            label: name.to_string(),
+            kind: Some(CompletionItemKind::KEYWORD),
+            detail: Some(detail.to_string()),
+            ..CompletionItem::default()
+        });
+        let registers = REGISTERS.iter().map(|name| CompletionItem {
+            label: name.to_string(),
+            kind: Some(CompletionItemKind::VARIABLE),
+            ..CompletionItem::default()
+        });
+        let table = self.documents.get(uri).and_then(|source| SymbolTable::analyze(source).ok());
+        let symbols = table.into_iter().flat_map(|t| t.symbols).map(|symbol| CompletionItem {
+            kind: Some(match symbol.kind {
+                SymbolKind::Label => CompletionItemKind::REFERENCE,
+                SymbolKind::Macro => CompletionItemKind::FUNCTION,
+                SymbolKind::Constant => CompletionItemKind::CONSTANT,
+            }),
+            detail: (symbol.kind == SymbolKind::Constant).then_some(symbol.value),
+            label: symbol.name,
+            ..CompletionItem::default()
+        });
+        mnemonics.chain(registers).chain(symbols).collect()
+    }
+
+    fn document_symbols(&self, uri: &Url) -> Option<Vec<DocumentSymbol>> {
+        let source = self.documents.get(uri)?;
+        let table = SymbolTable::analyze(source).ok()?;
+        let symbols = table
+            .symbols
+            .into_iter()
+            .map(|symbol| {
+                // `deprecated` has to be set, even though it is deprecated in favour of tags.
+                #[allow(deprecated)]
+                DocumentSymbol {
+                    name: symbol.name,
+                    detail: (symbol.kind == SymbolKind::Constant).then_some(symbol.value),
+                    kind: match symbol.kind {
+                        SymbolKind::Label => lsp_types::SymbolKind::KEY,
+                        SymbolKind::Macro => lsp_types::SymbolKind::FUNCTION,
+                        SymbolKind::Constant => lsp_types::SymbolKind::CONSTANT,
+                    },
+                    tags: None,
+                    deprecated: None,
+                    range: to_range(source, &symbol.span),
+                    selection_range: to_range(source, &symbol.name_span),
+                    children: None,
+                }
+            })
+            .collect();
+        Some(symbols)
+    }
+}
+
+fn cast_request<R: lsp_types::request::Request>(request: Request) -> Result<R::Params> {
+    match request.extract(R::METHOD) {
+        Ok((_, params)) => Ok(params),
+        Err(ExtractError::JsonError { method, error }) => Err(format!("Invalid parameters for {method}: {error}").into()),
+        Err(ExtractError::MethodMismatch(r)) => Err(format!("Expected a {} request, got {}", R::METHOD, r.method).into()),
+    }
+}
+
+fn cast_notification<N: lsp_types::notification::Notification>(
+    notification: Notification,
+) -> std::result::Result<N::Params, Notification> {
+    match notification.extract(N::METHOD) {
+        Ok(params) => Ok(params),
+        // Notifications with parameters the server does not understand are ignored, like unknown notifications.
+        Err(ExtractError::JsonError { method, .. }) => Err(Notification::new(method, serde_json::Value::Null)),
+        Err(ExtractError::MethodMismatch(n)) => Err(n),
+    }
+}
+
+/// Turns a byte offset into a position, which counts columns in UTF-16 code units like the protocol does.
+fn to_position(source: &str, offset: usize) -> Position {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |v| v + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+fn to_offset(source: &str, position: Position) -> usize {
+    let line_start: usize = source.split_inclusive('\n').take(position.line as usize).map(str::len).sum();
+    let mut column = 0;
+    for (i, c) in source[line_start..].char_indices() {
+        if column >= position.character as usize || c == '\n' {
+            return line_start + i;
+        }
+        column += c.len_utf16();
+    }
+    source.len()
+}
+
+/// Options that find the files a document includes on disk, relative to the document. Documents that are not files
+/// can not include anything.
+fn parse_options(uri: &Url) -> ParseOptions<'static> {
+    match uri.to_file_path() {
+        Ok(path) => ParseOptions {
+            file_name: std::fs::canonicalize(&path).unwrap_or(path).display().to_string(),
+            resolver: &FileResolver,
+            ..ParseOptions::default()
+        },
+        Err(()) => ParseOptions::default(),
+    }
+}
+
+fn to_range(source: &str, span: &Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(to_position(source, span.start), to_position(source, span.end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        // `é` is one code unit and `😀` is two, but they are two and four bytes.
+        let source = "ab\né😀x\n";
+        let x = source.find('x').unwrap();
+        assert_eq!(to_position(source, 0), Position::new(0, 0));
+        assert_eq!(to_position(source, 2), Position::new(0, 2));
+        assert_eq!(to_position(source, 3), Position::new(1, 0));
+        assert_eq!(to_position(source, 3 + "é".len()), Position::new(1, 1));
+        assert_eq!(to_position(source, x), Position::new(1, 3));
+        assert_eq!(to_position(source, source.len()), Position::new(2, 0));
+        assert_eq!(to_position(source, 100), Position::new(2, 0));
+    }
+
+    #[test]
+    fn offsets_count_utf16_code_units() {
+        let source = "ab\né😀x\n";
+        let x = source.find('x').unwrap();
+        assert_eq!(to_offset(source, Position::new(0, 0)), 0);
+        assert_eq!(to_offset(source, Position::new(1, 0)), 3);
+        assert_eq!(to_offset(source, Position::new(1, 1)), 3 + "é".len());
+        assert_eq!(to_offset(source, Position::new(1, 3)), x);
+        // Positions past the end of a line are at the end of the line, and positions past the last line are at the end.
+        assert_eq!(to_offset(source, Position::new(0, 10)), 2);
+        assert_eq!(to_offset(source, Position::new(5, 0)), source.len());
+        for offset in [0, 1, 2, 3, 3 + "é".len(), x, source.len()] {
+            assert_eq!(to_offset(source, to_position(source, offset)), offset);
+        }
+    }
+
+    #[test]
+    fn invalid_requests_get_an_error_response() {
+        let server = Server::default();
+        let request = Request::new(1.into(), GotoDefinition::METHOD.to_string(), serde_json::json!({"position": 3}));
+        let response = server.respond(request);
+        assert_eq!(response.id, 1.into());
+        assert!(response.result.is_none());
+        assert_eq!(response.error.unwrap().code, ErrorCode::InvalidParams as i32);
+
+        let request = Request::new(2.into(), "unknown/method".to_string(), serde_json::Value::Null);
+        let response = server.respond(request);
+        assert_eq!(response.result, Some(serde_json::Value::Null));
+        assert!(response.error.is_none());
+    }
+}
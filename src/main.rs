@@ -4,13 +4,28 @@ extern crate core;
 
 use std::fs::File;
 use std::io::{stdin, stdout, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::thread;
 
-use clap::{Args, Parser, Subcommand};
-use color_eyre::eyre::{eyre, Result};
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result, WrapErr};
 
-use assembler::parser::parse;
-use bytecode_interpreter::run::{interpret, Input, Output};
+use assembler::diagnostics;
+use assembler::export;
+use assembler::formatter;
+use assembler::include::FileResolver;
+use assembler::parser::{parse_with, ParseOptions, ScratchPolicy, SuccessfulParse};
+use assembler::turing_complete;
+use assembler::verilog;
+use bytecode_interpreter::container::{check_length, Container};
+use bytecode_interpreter::network::{Network, NodeState};
+use bytecode_interpreter::run::{Extension, Input, Machine, Output};
+use bytecode_interpreter::timing::TimingModel;
+
+use crate::topology::Topology;
+
+mod topology;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -27,7 +42,141 @@ enum Commands {
     AssembleAndRun(AssembleAndRun),
     #[clap(alias = "a")]
     Assemble(Assemble),
+    #[clap(alias = "n")]
+    Network(NetworkArgs),
+    Fmt(Fmt),
+}
+
+/// Optional instruction set extensions that can be enabled when running a program.
+#[derive(Clone, Copy, ArgEnum)]
+enum ExtensionArg {
+    /// Shift, rotate, multiply, increment and decrement instructions.
+    ExtendedAlu,
+    /// Conditional jumps that test any register, or compare reg1 with reg2.
+    RegisterConditionals,
+    /// Timer and input interrupts.
+    Interrupts,
+}
+
+impl ExtensionArg {
+    fn flags(extensions: &[Self]) -> u8 {
+        extensions.iter().fold(Extension::NONE, |flags, extension| {
+            flags
+                | match extension {
+                    Self::ExtendedAlu => Extension::EXTENDED_ALU,
+                    Self::RegisterConditionals => Extension::REGISTER_CONDITIONALS,
+                    Self::Interrupts => Extension::INTERRUPTS,
+                }
+        })
+    }
+}
+/// The formats assembled programs can be written in.
+#[derive(Clone, Copy, ArgEnum)]
+enum Format {
+    /// The raw bytecode, which can be run with the run command.
+    Raw,
+    /// Text that can be pasted into the program component in Turing Complete.
+    TuringComplete,
+    /// A container with the bytecode, the input, the labels and the source lines of every instruction, which can be run with the run command.
+    Container,
+    /// Intel HEX, which most EEPROM programmers and simulators can load.
+    IntelHex,
+    /// A ROM image for Logisim-evolution.
+    Logisim,
+    /// A ROM image for the Digital simulator.
+    Digital,
+    /// A C header that defines the program as a byte array.
+    CArray,
+    /// Rust source that defines the program as a byte array.
+    RustArray,
+    /// Two digit hex numbers separated by spaces.
+    Hex,
+    /// A Verilog module of the CPU with the program in its ROM. Only supports the base instruction set.
+    Verilog,
+}
+
+/// The assembly languages source files can be written in.
+#[derive(Clone, Copy, ArgEnum)]
+enum Dialect {
+    /// The assembly language of this VM.
+    Myvm,
+    /// The assembly language of the program component in Turing Complete.
+    TuringComplete,
+}
+
+impl Dialect {
+    /// Guesses the dialect from the extension of the source file. Files ending in .asm are Turing Complete programs.
+    fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|e| e == "asm") {
+            Self::TuringComplete
+        } else {
+            Self::Myvm
+        }
+    }
+}
+
+/// Which registers the code that loads labels above 63 may overwrite.
+#[derive(Clone, Copy, ArgEnum)]
+enum ScratchArg {
+    /// reg1, reg2 and reg3 are overwritten, so conditional jumps to the label test its address instead of reg3.
+    Clobber,
+    /// reg3 is saved in reg5 and restored, so conditional jumps still test reg3. reg1, reg2 and reg5 are overwritten.
+    KeepReg3,
+    /// Labels above 63 are an error.
+    Forbid,
+}
+
+impl From<ScratchArg> for ScratchPolicy {
+    fn from(scratch: ScratchArg) -> Self {
+        match scratch {
+            ScratchArg::Clobber => Self::Clobber,
+            ScratchArg::KeepReg3 => Self::KeepReg3,
+            ScratchArg::Forbid => Self::Forbid,
+        }
+    }
 }
+
+// Options for how programs are executed. This is a plain comment, since clap would use a doc comment as the description
+// of every subcommand that flattens these options.
+#[derive(Args)]
+struct MachineArgs {
+    /// Instruction set extensions to enable. Can be given multiple times.
+    #[clap(short = 'x', long = "extension", arg_enum, value_name = "EXTENSION")]
+    extensions: Vec<ExtensionArg>,
+    /// Print a score summary with the number of ticks the program took to STDERR once it halts.
+    #[clap(long)]
+    score: bool,
+    /// How many ticks each kind of instruction takes when scoring, as a comma separated list like "taken-jump=1,input=2".
+    ///
+    /// The kinds are literal, conditional, move, arithmetic, taken-jump, input and output.
+    /// By default every instruction takes one tick, like in Turing Complete.
+    #[clap(long, value_name = "TIMING")]
+    timing: Option<TimingModel>,
+}
+
+impl MachineArgs {
+    /// Runs `program` to completion with the extensions from the command line and `extensions`, printing its score if asked to.
+    fn run(&self, program: &[u8], input: Input, output: Output, extensions: u8) -> Result<u8> {
+        let extensions = ExtensionArg::flags(&self.extensions) | extensions;
+        check_length(program)?;
+        let mut machine = Machine::new(program, input, output, extensions);
+        if let Some(timing) = self.timing {
+            machine.set_timing_model(timing);
+        }
+        let exit_status = machine.run()?;
+        if self.score {
+            // Make sure the output of the program comes before the score.
+            stdout().flush()?;
+            eprintln!("Score:");
+            eprintln!("    Ticks: {}", machine.ticks());
+            eprintln!("    Instructions executed: {}", machine.instructions_executed());
+            eprintln!("    Program size: {} bytes", program.len());
+        }
+        Ok(exit_status)
+    }
+}
+
+/// Runs an assembled program, either raw bytecode or a container.
 #[derive(Args)]
 struct Run {
     /// The path to the program to execute
@@ -35,7 +184,7 @@ struct Run {
     program_path: PathBuf,
     /// The path to the file the program should get its input from.
     ///
-    ///If no file is specified the input get read from STDIN.
+    ///If no file is specified the input get read from STDIN, unless the program is a container with an input section.
     ///
     /// If you are
     #[clap(short, long, parse(from_os_str), value_name = "INPUT_FILE")]
@@ -45,8 +194,14 @@ struct Run {
     /// If no file is specified the output gets dumped to STDOUT.
     #[clap(short, long, parse(from_os_str), value_name = "OUTPUT_FILE")]
     output_path: Option<PathBuf>,
+    /// Read the input from STDIN even if the program is a container with an input section.
+    #[clap(short, long)]
+    use_stdin: bool,
+    #[clap(flatten)]
+    machine: MachineArgs,
 }
 
+/// Assembles a source file and runs it right away.
 #[derive(Args)]
 struct AssembleAndRun {
     /// The path to the source code to be assembled.
@@ -60,8 +215,17 @@ struct AssembleAndRun {
     /// If use_stdin is set, then the program will use STDIN for its input instead of the input inside the assembly file.
     #[clap(short, long)]
     use_stdin: bool,
+    /// The assembly language the source is written in. By default files ending in .asm are assumed to be Turing Complete programs.
+    #[clap(short, long, arg_enum, value_name = "DIALECT")]
+    dialect: Option<Dialect>,
+    /// Which registers the code that loads labels above 63 into reg0 may overwrite.
+    #[clap(long, arg_enum, default_value = "forbid", value_name = "POLICY")]
+    scratch_registers: ScratchArg,
+    #[clap(flatten)]
+    machine: MachineArgs,
 }
 
+/// Assembles a source file into bytecode or one of the other formats.
 #[derive(Args)]
 struct Assemble {
     /// The path to the source code to be assembled.
@@ -83,56 +247,227 @@ struct Assemble {
         value_name = "GENERATED_INPUT_FILE"
     )]
     generated_input_path: Option<PathBuf>,
+    /// The format the generated program is written in.
+    #[clap(short, long, arg_enum, default_value = "raw")]
+    format: Format,
+    /// The path to the file into which a Verilog testbench should go, when the format is verilog.
+    ///
+    /// The testbench feeds the program the input from its input section and checks that it outputs the same as in the interpreter.
+    #[clap(short = 't', long, parse(from_os_str), value_name = "GENERATED_TESTBENCH_FILE")]
+    generated_testbench_path: Option<PathBuf>,
+    /// The maximum number of clock cycles the Verilog testbench runs the program for.
+    #[clap(long, default_value_t = 100_000)]
+    testbench_cycles: u64,
+    /// Instruction set extensions to record in the container format, on top of the ones the instructions of the program use. Can be given multiple times.
+    #[clap(short = 'x', long = "extension", arg_enum, value_name = "EXTENSION")]
+    extensions: Vec<ExtensionArg>,
+    /// The assembly language the source is written in. By default files ending in .asm are assumed to be Turing Complete programs.
+    #[clap(short, long, arg_enum, value_name = "DIALECT")]
+    dialect: Option<Dialect>,
+    /// Which registers the code that loads labels above 63 into reg0 may overwrite.
+    #[clap(long, arg_enum, default_value = "forbid", value_name = "POLICY")]
+    scratch_registers: ScratchArg,
+}
+
+/// Formats source files in place.
+#[derive(Args)]
+struct Fmt {
+    /// The source files to format.
+    #[clap(required = true, parse(from_os_str), value_name = "SOURCE_FILE")]
+    source_paths: Vec<PathBuf>,
+    /// Do not change any files, but list the files that are not formatted and exit with the status 1 if there are any.
+    #[clap(long)]
+    check: bool,
+}
+
+/// Runs several programs as a network, where the output of one program is fed into the input of others.
+#[derive(Args)]
+struct NetworkArgs {
+    /// The path to the topology file that describes the network.
+    ///
+    /// Every line of a topology file either declares a node with "node NAME PROGRAM_PATH", or connects the output of one node to the input of another with "FROM -> TO".
+    /// Programs ending in .myvm or .asm are assembled and start out with the input from their input section.
+    #[clap(short, long, parse(from_os_str), value_name = "TOPOLOGY_FILE")]
+    topology_path: PathBuf,
+    /// Stop the network after this many rounds, where every node executes one instruction per round.
+    #[clap(short, long, default_value_t = 1_000_000)]
+    max_rounds: u64,
+    /// Instruction set extensions to enable for every node. Can be given multiple times.
+    #[clap(short = 'x', long = "extension", arg_enum, value_name = "EXTENSION")]
+    extensions: Vec<ExtensionArg>,
+    /// Which registers the code that loads labels above 63 into reg0 may overwrite, in every program that is assembled.
+    #[clap(long, arg_enum, default_value = "forbid", value_name = "POLICY")]
+    scratch_registers: ScratchArg,
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
     let args: Cli = Cli::parse();
-    match args.command {
-        Commands::Run(r) => run(r),
-        Commands::Assemble(a) => assemble(a),
-        Commands::AssembleAndRun(ar) => assemble_and_run(ar),
-    }
+    let exit_status = match args.command {
+        Commands::Run(r) => run(r)?,
+        Commands::Assemble(a) => {
+            assemble(a)?;
+            0
+        }
+        Commands::AssembleAndRun(ar) => assemble_and_run(ar)?,
+        Commands::Network(n) => {
+            network(n)?;
+            0
+        }
+        Commands::Fmt(f) => fmt(f)?,
+    };
+    // Programs choose their exit status with the halt instruction, so scripts can branch on it.
+    std::process::exit(exit_status.into())
 }
 
-fn run(args: Run) -> Result<()> {
-    let input = handle_input(args.input_path)?;
+fn run(args: Run) -> Result<u8> {
     let program = handle_program(args.program_path)?;
+    let extensions = ExtensionArg::flags(&args.machine.extensions) | program.extensions;
+    let input = if args.input_path.is_none() && !args.use_stdin && !program.input.is_empty() {
+        Input::ARRAY(&program.input)
+    } else {
+        handle_interrupt_input(handle_input(args.input_path)?, extensions)
+    };
     let output = handle_output(args.output_path)?;
 
-    interpret(&program, input, output)?;
-
-    Ok(())
+    args.machine.run(&program.code, input, output, program.extensions)
 }
 
 fn assemble(args: Assemble) -> Result<()> {
-    let source = handle_source(args.source_path)?;
-    let ast = parse(&source)?;
-    let (input, program, _) = ast.into_raw_parts();
+    let ast = handle_assembly(args.source_path, args.dialect, args.scratch_registers.into())?;
+    if matches!(args.format, Format::Raw) && Container::is_container(ast.program()) {
+        return Err(eyre!("The program starts with the same bytes as a container, so it would be loaded as one. Use --format container instead."));
+    }
     let mut program_file = File::create(args.generated_program_path)?;
-    program_file.write_all(&program)?;
+    match args.format {
+        Format::Raw => program_file.write_all(ast.program())?,
+        Format::TuringComplete => program_file.write_all(export::to_turing_complete(&ast).as_bytes())?,
+        Format::Container => export::to_container(&ast, ExtensionArg::flags(&args.extensions) | ast.extensions()).write_to(&mut program_file)?,
+        Format::IntelHex => program_file.write_all(export::to_intel_hex(ast.program()).as_bytes())?,
+        Format::Logisim => program_file.write_all(export::to_logisim(ast.program()).as_bytes())?,
+        Format::Digital => program_file.write_all(export::to_digital(ast.program()).as_bytes())?,
+        Format::CArray => program_file.write_all(export::to_c_array(ast.program()).as_bytes())?,
+        Format::RustArray => program_file.write_all(export::to_rust_array(ast.program()).as_bytes())?,
+        Format::Hex => program_file.write_all(export::to_hex(ast.program()).as_bytes())?,
+        Format::Verilog => program_file.write_all(verilog::to_verilog(ast.program()).as_bytes())?,
+    }
+    if let Some(testbench_path) = args.generated_testbench_path {
+        if !matches!(args.format, Format::Verilog) {
+            return Err(eyre!("Testbenches can only be generated with the verilog format."));
+        }
+        let mut testbench_file = File::create(testbench_path)?;
+        testbench_file.write_all(verilog::to_verilog_testbench(&ast, args.testbench_cycles)?.as_bytes())?;
+    }
     if let Some(input_path) = args.generated_input_path {
         let mut input_file = File::create(input_path)?;
-        input_file.write_all(&input)?;
+        input_file.write_all(ast.input())?;
     }
     Ok(())
 }
 
-fn assemble_and_run(args: AssembleAndRun) -> Result<()> {
-    let source = handle_source(args.source_path)?;
-    let ast = parse(&source)?;
+fn assemble_and_run(args: AssembleAndRun) -> Result<u8> {
+    let ast = handle_assembly(args.source_path, args.dialect, args.scratch_registers.into())?;
+    let program_extensions = ast.extensions();
     let (input_vec, program_vec, _) = ast.into_raw_parts();
 
+    let extensions = ExtensionArg::flags(&args.machine.extensions) | program_extensions;
     let input = if args.use_stdin {
-        Input::STDIN(stdin())
+        handle_interrupt_input(Input::STDIN(stdin()), extensions)
     } else {
         Input::ARRAY(&input_vec)
     };
     let output = handle_output(args.output_path)?;
-    interpret(&program_vec, input, output)?;
+    args.machine.run(&program_vec, input, output, program_extensions)
+}
+
+fn network(args: NetworkArgs) -> Result<()> {
+    let base_dir = args
+        .topology_path
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    let topology = Topology::parse(&handle_source(args.topology_path)?, &base_dir)?;
+    // Load every program before building the network, since the nodes borrow their programs.
+    let mut programs = Vec::with_capacity(topology.nodes.len());
+    // Every node runs with the extensions that any of the programs needs.
+    let mut extensions = ExtensionArg::flags(&args.extensions);
+    for (_, path) in &topology.nodes {
+        if path.extension().is_some_and(|e| e == "myvm" || e == "asm") {
+            let ast = handle_assembly(path.clone(), None, args.scratch_registers.into())?;
+            extensions |= ast.extensions();
+            let (input, program, _) = ast.into_raw_parts();
+            check_length(&program)?;
+            programs.push((program, input));
+        } else {
+            let container = handle_program(path.clone())?;
+            extensions |= container.extensions;
+            programs.push((container.code, container.input));
+        }
+    }
+
+    let mut network = Network::new(extensions);
+    for ((name, _), (program, input)) in topology.nodes.iter().zip(&programs) {
+        network.add_node(name, program, input);
+    }
+    for (from, to) in &topology.edges {
+        // The topology has already checked that every edge uses declared nodes.
+        network.connect(network.node(from).unwrap(), network.node(to).unwrap())?;
+    }
+
+    let mut stdout = stdout();
+    for report in network.run(args.max_rounds)? {
+        let state = match report.state {
+            NodeState::Halted(status) => format!("halted with status {status}"),
+            NodeState::WaitingForInput => "waiting for input".to_string(),
+            NodeState::Running => "still running".to_string(),
+        };
+        writeln!(stdout, "== {} ({state}) ==", report.name)?;
+        stdout.write_all(&report.output)?;
+        writeln!(stdout)?;
+    }
     Ok(())
 }
 
+fn fmt(args: Fmt) -> Result<u8> {
+    let mut unformatted = false;
+    for path in args.source_paths {
+        let source = handle_source(path.clone())?;
+        let formatted = formatter::format(&source).wrap_err_with(|| format!("Could not format {}", path.display()))?;
+        if formatted == source {
+            continue;
+        }
+        if args.check {
+            println!("{} is not formatted", path.display());
+            unformatted = true;
+        } else {
+            File::create(&path)?.write_all(formatted.as_bytes())?;
+        }
+    }
+    Ok(unformatted.into())
+}
+
+/// STDIN can not be checked for available input without blocking, so when interrupts are enabled we read it on a background thread instead.
+fn handle_interrupt_input(input: Input, extensions: u8) -> Input {
+    if extensions & Extension::INTERRUPTS == 0 {
+        return input;
+    }
+    match input {
+        Input::STDIN(mut s) => {
+            let (sender, receiver) = channel();
+            thread::spawn(move || {
+                let mut buf = [0u8; 1];
+                while let Ok(1) = s.read(&mut buf) {
+                    if sender.send(buf[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+            Input::CHANNEL(receiver)
+        }
+        other => other,
+    }
+}
+
 fn handle_input<'a>(input: Option<PathBuf>) -> Result<Input<'a>> {
     match input {
         None => Ok(Input::STDIN(stdin())),
@@ -157,14 +492,47 @@ fn handle_output<'a>(output: Option<PathBuf>) -> Result<Output<'a>> {
     }
 }
 
-fn handle_program(program_path: PathBuf) -> Result<Vec<u8>> {
+/// Loads a program container, or raw bytecode if the file does not start with a container header.
+fn handle_program(program_path: PathBuf) -> Result<Container> {
     let mut program_file = match File::open(program_path) {
         Ok(v) => v,
         Err(e) => return Err(eyre!("Failed at opening the program file you provided. This is most likely because the path you provided was bad. Here is the precise error: {e}")),
     };
-    let mut program_buf = Vec::new();
-    let _ = program_file.read_to_end(&mut program_buf)?;
-    Ok(program_buf)
+    Container::read_from(&mut program_file)
+}
+
+/// Assembles the source file in `dialect`, or in the dialect its extension suggests if no dialect is given. `scratch` only applies to myvm programs.
+fn handle_assembly(source_path: PathBuf, dialect: Option<Dialect>, scratch: ScratchPolicy) -> Result<SuccessfulParse> {
+    let dialect = dialect.unwrap_or_else(|| Dialect::from_path(&source_path));
+    let source = handle_source(source_path.clone())?;
+    match dialect {
+        Dialect::Myvm => {
+            let options = ParseOptions {
+                scratch,
+                // Includes are found relative to the file, and the file is told apart from the files it includes by its full path.
+                file_name: std::fs::canonicalize(&source_path).unwrap_or_else(|_| source_path.clone()).display().to_string(),
+                resolver: &FileResolver,
+            };
+            let parsed = parse_with(&source, &options).map_err(|e| {
+                // Print every problem with a snippet of the source, and only return a summary. Problems in included files are
+                // reported at the include directive in this file, with a note that says where in the included file they are.
+                match diagnostics::render_error(&e, &source, &source_path.display().to_string()) {
+                    Some(rendered) => {
+                        eprintln!("{rendered}");
+                        let count = diagnostics::to_diagnostics(&e).len();
+                        let errors = if count == 1 { "error" } else { "errors" };
+                        eyre!("Could not assemble {} because of {count} {errors}.", source_path.display())
+                    }
+                    None => e,
+                }
+            })?;
+            for warning in parsed.warnings() {
+                eprintln!("{}", warning.render(&source, &source_path.display().to_string()));
+            }
+            Ok(parsed)
+        }
+        Dialect::TuringComplete => turing_complete::parse(&source),
+    }
 }
 
 fn handle_source(source_path: PathBuf) -> Result<String> {
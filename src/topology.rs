@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Describes a network of programs, where the output of one program is fed into the input of others.
+///
+/// A topology file has one declaration per line:
+/// ```text
+/// // Comments start with two slashes.
+/// node producer producer.myvm
+/// node consumer consumer.vmp
+/// producer -> consumer
+/// ```
+/// Program paths are relative to the topology file. Programs ending in `.myvm` or `.asm` are assembled, anything else is treated as assembled bytecode.
+pub struct Topology {
+    pub nodes: Vec<(String, PathBuf)>,
+    pub edges: Vec<(String, String)>,
+}
+
+impl Topology {
+    pub fn parse(source: &str, base_dir: &Path) -> Result<Self> {
+        let mut nodes: Vec<(String, PathBuf)> = Vec::new();
+        let mut edges = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = match line.find("//") {
+                Some(v) => &line[..v],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["node", name, path] => {
+                    if nodes.iter().any(|(n, _)| n == name) {
+                        return Err(eyre!("Node \"{name}\" on line {line_number} of the topology file is declared more than once."));
+                    }
+                    nodes.push((name.to_string(), base_dir.join(path)));
+                }
+                [from, "->", to] => edges.push((from.to_string(), to.to_string())),
+                _ => return Err(eyre!("Could not understand line {line_number} of the topology file: \"{line}\". Lines should either look like \"node NAME PROGRAM_PATH\" or \"FROM -> TO\".")),
+            }
+        }
+        for (from, to) in &edges {
+            for name in [from, to] {
+                if !nodes.iter().any(|(n, _)| n == name) {
+                    return Err(eyre!("The edge {from} -> {to} uses the node \"{name}\", which is not declared in the topology file."));
+                }
+            }
+        }
+        Ok(Self { nodes, edges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Topology;
+
+    #[test]
+    fn parses_nodes_and_edges() {
+        let source = "// A comment.\nnode producer producer.myvm\n\n  node consumer  out/consumer.vmp // Trailing comment.\nproducer -> consumer\n";
+        let topology = Topology::parse(source, Path::new("networks")).unwrap();
+        assert_eq!(
+            topology.nodes,
+            [
+                ("producer".to_string(), PathBuf::from("networks/producer.myvm")),
+                ("consumer".to_string(), PathBuf::from("networks/out/consumer.vmp")),
+            ]
+        );
+        assert_eq!(topology.edges, [("producer".to_string(), "consumer".to_string())]);
+    }
+
+    #[test]
+    fn duplicate_nodes_are_an_error() {
+        let error = Topology::parse("node a a.myvm\nnode a b.myvm\n", Path::new("")).err().unwrap();
+        assert!(error.to_string().contains("\"a\" on line 2"), "{error}");
+    }
+
+    #[test]
+    fn edges_to_unknown_nodes_are_an_error() {
+        let error = Topology::parse("node a a.myvm\na -> b\n", Path::new("")).err().unwrap();
+        assert!(error.to_string().contains("uses the node \"b\""), "{error}");
+    }
+
+    #[test]
+    fn malformed_lines_are_an_error() {
+        for line in ["node a", "node a b c", "a => b", "a -> b -> c", "connect a b"] {
+            let error = Topology::parse(&format!("node a a.myvm\nnode b b.myvm\n{line}\n"), Path::new("")).err().unwrap();
+            assert!(error.to_string().contains("Could not understand line 3"), "{line}: {error}");
+        }
+    }
+}
@@ -1,3 +1,11 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use assembler::diagnostics::{diagnose, diagnose_with, Diagnostic, LineColumn, Severity};
+use assembler::include::VirtualFileSystem;
+use assembler::parser::{parse_with, ParseOptions};
+use bytecode_interpreter::run::{Input, Machine, Output, Queue, Step, MAX_PROGRAM_LENGTH};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 type JSResult<T> = Result<T, JsValue>;
@@ -41,3 +49,388 @@ pub fn parse_wasm_edition(program: &str) -> JSResult<WasmSuccessfulParse> {
         Err(e) => Err(JsValue::from(e.to_string())),
     }
 }
+
+/// Files that programs can include, since the browser has no file system. Names are paths separated with `/`.
+#[wasm_bindgen]
+pub struct WasmFiles {
+    files: VirtualFileSystem,
+}
+
+#[wasm_bindgen]
+impl WasmFiles {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            files: VirtualFileSystem::new(),
+        }
+    }
+
+    /// Adds the file `name`, or replaces it if it was already added.
+    pub fn insert(&mut self, name: &str, source: String) {
+        self.files.insert(name, source);
+    }
+}
+
+impl WasmFiles {
+    fn options(&self, file_name: &str) -> ParseOptions<'_> {
+        ParseOptions {
+            file_name: file_name.to_string(),
+            resolver: &self.files,
+            ..ParseOptions::default()
+        }
+    }
+}
+
+impl Default for WasmFiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Assembles `program`, which is the file `file_name`, with `include` directives reading the other files in `files`.
+#[wasm_bindgen]
+pub fn parse_with_files_wasm_edition(program: &str, file_name: &str, files: &WasmFiles) -> JSResult<WasmSuccessfulParse> {
+    match parse_with(program, &files.options(file_name)) {
+        Ok(v) => {
+            let r = v.into_raw_parts();
+            Ok(WasmSuccessfulParse::from(r.0, r.1, r.2))
+        }
+        Err(e) => Err(JsValue::from(e.to_string())),
+    }
+}
+
+
+/// An error from the machine, with `kind` telling JS consumers what went wrong without parsing `message`.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct WasmMachineError {
+    kind: String,
+    message: String,
+    pc: u8,
+}
+
+#[wasm_bindgen]
+impl WasmMachineError {
+    /// One of "invalid_program" or "runtime".
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    /// The address of the instruction that failed.
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> u8 {
+        self.pc
+    }
+}
+
+impl WasmMachineError {
+    fn new(kind: &str, message: String, pc: u8) -> Self {
+        Self {
+            kind: kind.to_string(),
+            message,
+            pc,
+        }
+    }
+}
+
+/// A machine that runs programs with the same semantics as the native runtime.
+///
+/// Input is pushed with `push_input` and output is collected with `take_output`. Reading from an empty input pauses the machine until more input is pushed.
+#[wasm_bindgen]
+pub struct WasmMachine {
+    machine: Machine<'static>,
+    input: Queue,
+    output: Queue,
+}
+
+#[wasm_bindgen]
+impl WasmMachine {
+    /// Creates a machine running `program` with the instruction set extensions in `extensions`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: Vec<u8>, extensions: u8) -> Result<WasmMachine, WasmMachineError> {
+        if program.len() > MAX_PROGRAM_LENGTH {
+            return Err(WasmMachineError::new(
+                "invalid_program",
+                format!("Programs cannot be longer than 255 bytes, but this one is {} bytes.", program.len()),
+                0,
+            ));
+        }
+        let input: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let output: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let machine = Machine::new_owned(program, Input::QUEUE(input.clone()), Output::QUEUE(output.clone()), extensions);
+        Ok(Self {
+            machine,
+            input,
+            output,
+        })
+    }
+
+    /// Executes one instruction. Returns "running", "halted" or "waiting_for_input".
+    pub fn step(&mut self) -> Result<String, WasmMachineError> {
+        let pc = self.machine.pc();
+        match self.machine.step() {
+            Ok(Step::Running) => Ok("running".to_string()),
+            Ok(Step::Halted(_)) => Ok("halted".to_string()),
+            Ok(Step::WaitingForInput) => Ok("waiting_for_input".to_string()),
+            Err(e) => Err(WasmMachineError::new("runtime", e.to_string(), pc)),
+        }
+    }
+
+    /// Steps the machine until it stops running or has executed `max_steps` instructions. Returns the result of the last step.
+    pub fn run_for(&mut self, max_steps: u32) -> Result<String, WasmMachineError> {
+        let mut state = "running".to_string();
+        for _ in 0..max_steps {
+            state = self.step()?;
+            if state != "running" {
+                break;
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn registers(&self) -> Vec<u8> {
+        self.machine.registers().to_vec()
+    }
+
+    pub fn pc(&self) -> u8 {
+        self.machine.pc()
+    }
+
+    /// The exit status of the program, or undefined if it has not halted.
+    pub fn exit_status(&self) -> Option<u8> {
+        self.machine.exit_status()
+    }
+
+    pub fn push_input(&mut self, bytes: Vec<u8>) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    /// Removes and returns everything the program has output since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.borrow_mut().drain(..).collect()
+    }
+}
+
+
+/// A problem with a program, shaped like a marker for the Monaco editor. Lines and columns start at 1.
+#[wasm_bindgen]
+pub struct WasmDiagnostic {
+    severity: u8,
+    message: String,
+    start_line_number: usize,
+    start_column: usize,
+    end_line_number: usize,
+    end_column: usize,
+}
+
+#[wasm_bindgen]
+impl WasmDiagnostic {
+    /// The value of Monaco's MarkerSeverity, 8 for errors and 4 for warnings.
+    #[wasm_bindgen(getter)]
+    pub fn severity(&self) -> u8 {
+        self.severity
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    #[wasm_bindgen(getter = startLineNumber)]
+    pub fn start_line_number(&self) -> usize {
+        self.start_line_number
+    }
+
+    #[wasm_bindgen(getter = startColumn)]
+    pub fn start_column(&self) -> usize {
+        self.start_column
+    }
+
+    #[wasm_bindgen(getter = endLineNumber)]
+    pub fn end_line_number(&self) -> usize {
+        self.end_line_number
+    }
+
+    #[wasm_bindgen(getter = endColumn)]
+    pub fn end_column(&self) -> usize {
+        self.end_column
+    }
+}
+
+/// Assembles `program` and returns every problem with it, pointing at the source the user wrote even inside macro expansions.
+#[wasm_bindgen]
+pub fn diagnose_wasm_edition(program: &str) -> Vec<WasmDiagnostic> {
+    to_markers(program, diagnose(program))
+}
+
+/// Like `diagnose_wasm_edition`, for the file `file_name` that can include the other files in `files`. Problems in
+/// included files point at the include directive.
+#[wasm_bindgen]
+pub fn diagnose_with_files_wasm_edition(program: &str, file_name: &str, files: &WasmFiles) -> Vec<WasmDiagnostic> {
+    to_markers(program, diagnose_with(program, &files.options(file_name)))
+}
+
+fn to_markers(program: &str, diagnostics: Vec<Diagnostic>) -> Vec<WasmDiagnostic> {
+    diagnostics
+        .into_iter()
+        .map(|d| {
+            let (start_line_number, start_column) = monaco_position(program, d.span.start);
+            let (end_line_number, mut end_column) = monaco_position(program, d.span.end);
+            // Monaco does not show markers that are empty.
+            if (start_line_number, start_column) == (end_line_number, end_column) {
+                end_column += 1;
+            }
+            WasmDiagnostic {
+                severity: match d.severity {
+                    Severity::Error => 8,
+                    Severity::Warning => 4,
+                },
+                message: d.full_message(),
+                start_line_number,
+                start_column,
+                end_line_number,
+                end_column,
+            }
+        })
+        .collect()
+}
+
+/// Monaco counts columns in UTF-16 code units, unlike the diagnostics which count characters.
+fn monaco_position(source: &str, offset: usize) -> (usize, usize) {
+    let line = LineColumn::of(source, offset).line;
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |v| v + 1);
+    (line, before[line_start..].encode_utf16().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::parser::parse;
+    use bytecode_interpreter::run::Extension;
+
+    fn machine_for(source: &str) -> WasmMachine {
+        let program = parse(source).unwrap().program().to_vec();
+        WasmMachine::new(program, Extension::NONE).unwrap()
+    }
+
+    #[test]
+    fn runs_programs() {
+        let mut machine = machine_for("program:\nmov in 1\nmov in 2\nadd\nmov 3 out\nhalt reg3\n");
+        machine.push_input(vec![3]);
+        assert_eq!(machine.step().unwrap(), "running");
+        assert_eq!(machine.step().unwrap(), "waiting_for_input");
+        assert_eq!(machine.pc(), 1);
+        assert_eq!(machine.exit_status(), None);
+        machine.push_input(vec![4]);
+        assert_eq!(machine.run_for(100).unwrap(), "halted");
+        assert_eq!(machine.exit_status(), Some(7));
+        assert_eq!(machine.registers()[1..4], [3, 4, 7]);
+        assert_eq!(machine.take_output(), [7]);
+        assert_eq!(machine.take_output(), []);
+    }
+
+    #[test]
+    fn run_for_stops_after_max_steps() {
+        let mut machine = machine_for("program:\nlabel loop:\n1\nmov 0 out\nloop\nj\n");
+        assert_eq!(machine.run_for(6).unwrap(), "running");
+        assert_eq!(machine.pc(), 2);
+        assert_eq!(machine.take_output(), [1, 1]);
+        assert_eq!(machine.run_for(0).unwrap(), "running");
+        assert_eq!(machine.pc(), 2);
+    }
+
+    #[test]
+    fn long_programs_are_invalid() {
+        let error = WasmMachine::new(vec![0; MAX_PROGRAM_LENGTH + 1], Extension::NONE).err().unwrap();
+        assert_eq!(error.kind(), "invalid_program");
+        assert!(error.message().contains("256 bytes"));
+        assert_eq!(error.pc(), 0);
+        assert!(WasmMachine::new(vec![0; MAX_PROGRAM_LENGTH], Extension::NONE).is_ok());
+    }
+
+    #[test]
+    fn runtime_errors_have_the_address_of_the_instruction() {
+        // An extended ALU instruction on a machine without the extension.
+        let mut machine = WasmMachine::new(vec![0, 0b11_001_000], Extension::NONE).unwrap();
+        let error = machine.run_for(100).unwrap_err();
+        assert_eq!(error.kind(), "runtime");
+        assert!(error.message().contains("extended ALU"));
+        assert_eq!(error.pc(), 1);
+
+        let mut machine = WasmMachine::new(vec![0b11_001_000], Extension::EXTENDED_ALU).unwrap();
+        assert_eq!(machine.step().unwrap(), "running");
+    }
+
+    fn position(marker: &WasmDiagnostic) -> (usize, usize, usize, usize) {
+        (marker.start_line_number, marker.start_column, marker.end_line_number, marker.end_column)
+    }
+
+    #[test]
+    fn positions_start_at_one() {
+        let source = "ab\ncd\n";
+        assert_eq!(monaco_position(source, 0), (1, 1));
+        assert_eq!(monaco_position(source, 2), (1, 3));
+        assert_eq!(monaco_position(source, 3), (2, 1));
+        assert_eq!(monaco_position(source, 5), (2, 3));
+        assert_eq!(monaco_position(source, 6), (3, 1));
+        assert_eq!(monaco_position(source, 100), (3, 1));
+    }
+
+    #[test]
+    fn columns_count_utf16_code_units() {
+        // `é` is one code unit and `😀` is two, but they are two and four bytes.
+        let source = "\né😀x";
+        assert_eq!(monaco_position(source, 1 + "é".len()), (2, 2));
+        assert_eq!(monaco_position(source, 1 + "é😀".len()), (2, 4));
+        assert_eq!(monaco_position(source, source.len()), (2, 5));
+
+        let markers = diagnose_wasm_edition("program:\n/* é😀 */ mov 0 7\n");
+        assert_eq!(markers.len(), 1);
+        assert_eq!(position(&markers[0]), (2, 11, 2, 14));
+    }
+
+    #[test]
+    fn markers_for_diagnostics() {
+        let markers = diagnose_wasm_edition("program:\nnop\nmov 0 7\n");
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].severity(), 8);
+        assert_eq!(position(&markers[0]), (3, 1, 3, 4));
+        assert!(markers[0].message().starts_with("Unknown identifier: mov\nhelp: "));
+
+        assert!(diagnose_wasm_edition("program:\nnop\n").is_empty());
+    }
+
+    #[test]
+    fn markers_in_macros_point_at_the_call() {
+        let source = "program:\nmacro m():\n    frob\nend_macro:\n/* é */ m()\n";
+        let markers = diagnose_wasm_edition(source);
+        assert_eq!(markers.len(), 1);
+        assert_eq!(position(&markers[0]), (5, 9, 5, 12));
+        assert!(markers[0].message().contains("\nnote: The error is in the code this macro call or constant expands to."));
+    }
+
+    #[test]
+    fn empty_markers_are_widened() {
+        let source = "a\nb";
+        let diagnostic = |severity, span| Diagnostic {
+            severity,
+            message: "message".to_string(),
+            span,
+            notes: vec!["note".to_string()],
+            help: None,
+        };
+        let markers = to_markers(source, vec![diagnostic(Severity::Warning, 2..2), diagnostic(Severity::Error, 0..2)]);
+        assert_eq!(markers[0].severity(), 4);
+        assert_eq!(position(&markers[0]), (2, 1, 2, 2));
+        assert_eq!(markers[0].message(), "message\nnote: note");
+        assert_eq!(markers[1].severity(), 8);
+        assert_eq!(position(&markers[1]), (1, 1, 2, 1));
+    }
+}